@@ -33,16 +33,64 @@ print(s.get_players())
 player = "default player"
 print("is " + player + " connected? " + str(s.is_connected(player)))
 
-# Check if another player is connected.
-player = "Henry"
+# Check if the player from clientconf.toml is connected.
+player = "Harold"
 print("is " + player + " connected? " + str(s.is_connected(player)))
 
+# Print the server's status. Monitoring scripts can get the same thing from
+# a running server without logging in, with hpserver.query_status("127.0.0.1:8080"),
+# or hpserver.query_status_tls("127.0.0.1:8080", None) if it has tls turned on.
+print("server status:")
+print(s.get_status())
+
+# Counters and gauges for monitoring. Turn on [server.metrics] in the
+# config to have Prometheus scrape the same ones over http.
+print("server metrics:")
+print(s.get_metrics())
+
+# How the game loop is keeping up, and the seed to replay this game with.
+print("tick stats:")
+print(s.get_tick_stats())
+
+# The kinds of unit, loaded from the files in data/units. Give the default
+# player a scout and move it 5 steps east. Units are looked up by the id
+# spawn_unit returns, which is also what clients see in get_world().
+print("unit types:")
+print(s.get_unit_types())
+scout = s.spawn_unit("default player", "scout", "Scout 1", 10, 10)
+s.move_unit(scout, 5, 0)
+print(s.get_unit(scout))
+
+# Leave the name out to have one made up from the syllables in data/names
+# for the player's faction. With the same seed, each player's units always
+# get the same names in the same order. gen_unit_name shows the name the
+# next one would get, without using it up.
+s.set_faction("default player", "machines")
+print(s.get_unit(s.spawn_unit("default player", "drone", None, 12, 10)))
+print("the next worker would be called: " + s.gen_unit_name("default player", "worker"))
+
+# Print the connect and disconnect events the server has seen so far.
+print("server events:")
+print(s.poll_events())
+
+# Commands players have sent, with the tick each one ran on. Record these
+# along with the seed from get_tick_stats() to replay a game.
+print("played commands:")
+print(s.poll_commands())
+
+# Take admin commands like "list", "kick" and "ban" from the terminal. Scripts
+# can run the same commands with s.admin("list").
+Thread(target=s.console, daemon=True).start()
+
 # Instantiate a new Client.
 c = hpclient.Client("clientconf.toml")
 
 # Print the client type.
 print("client type: " + c.client_type())
 
+# Find servers on the local network, waiting up to a second for answers.
+# print(c.discover_servers(1.0))
+
 # Connect in the background to the server listed in the clientconf.toml file.
 Thread(target=c.connect, daemon=True).start()
 
@@ -57,8 +105,20 @@ print(dir(c))
 print("client conf:")
 print(c.get_conf())
 
+# Say hello to everyone once connected, and print chat as it comes in with:
+# c.say("hello")
+# print(c.poll_chat())
+
+# Order the first of our units that the server has told us about 3 steps
+# north, once connected, with:
+# unit = c.get_world()["units"][0]
+# c.move_unit(unit["id"], 0, -3)
+
 # Load a 3D model that's in glTF format.
 # c.load_gltf("hpclient/assets/models/cube/cube.glb")
 
 # Start the client's main loop.
 c.run()
+
+# Disconnect everyone and close the server once the client window is closed.
+s.stop()
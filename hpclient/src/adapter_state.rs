@@ -8,11 +8,10 @@ pub struct AdapterState<B: Backend> {
 
 impl<B: Backend> AdapterState<B> {
   pub fn new(adapters: &mut Vec<Adapter<B>>) -> Self {
-    print!("Chosen: ");
-
     for adapter in adapters.iter() {
-      println!("{:?}", adapter.info);
+      info!("found adapter: {:?}", adapter.info);
     }
+    info!("using adapter: {}", adapters[0].info.name);
 
     AdapterState::<B>::new_adapter(adapters.remove(0))
   }
@@ -20,7 +19,7 @@ impl<B: Backend> AdapterState<B> {
   pub fn new_adapter(adapter: Adapter<B>) -> Self {
     let memory_types = adapter.physical_device.memory_properties().memory_types;
     let limits = adapter.physical_device.limits();
-    println!("{:?}", limits);
+    debug!("adapter limits: {:?}", limits);
 
     AdapterState {
       adapter: Some(adapter),
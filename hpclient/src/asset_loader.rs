@@ -17,8 +17,8 @@ pub fn load_gltf(path: &str) -> Result<GltfObject, Box<StdError>> {
   // let projection = GltfObject::get_projection(&w);
   // let projection = mat4_id();
 
-  println!("loaded gltf asset: {}", path);
-  println!("gltf_data scenes: {:#?}", gltf_data.scenes());
+  info!("loaded gltf asset: {}", path);
+  debug!("gltf asset {} scenes: {:#?}", path, gltf_data.scenes());
 
   Ok(GltfObject {
     data: gltf_data,
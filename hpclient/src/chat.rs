@@ -0,0 +1,49 @@
+use cpython::{PyDict, Python, ToPyObject};
+use hpnet::message::{ChatChannel, ChatMsg};
+use std::collections::VecDeque;
+
+// How many chat messages to keep for scripts that aren't collecting them.
+const MAX_UNREAD: usize = 500;
+
+// Chat messages the server delivered to us, waiting for a script to collect
+// them with Client.poll_chat().
+#[derive(Debug, Default)]
+pub struct ChatInbox {
+  messages: VecDeque<ChatMsg>,
+}
+
+impl ChatInbox {
+  pub fn push(&mut self, msg: ChatMsg) {
+    if self.messages.len() >= MAX_UNREAD {
+      self.messages.pop_front();
+    }
+    self.messages.push_back(msg);
+  }
+
+  // Take every message, oldest first.
+  pub fn drain(&mut self) -> Vec<ChatLine> {
+    self.messages.drain(..).map(ChatLine).collect()
+  }
+}
+
+// A chat message as handed to scripts.
+#[derive(Debug, Clone)]
+pub struct ChatLine(pub ChatMsg);
+
+impl ToPyObject for ChatLine {
+  type ObjectType = PyDict;
+
+  fn to_py_object(&self, py: Python) -> PyDict {
+    let to = match self.0.channel {
+      ChatChannel::Whisper(ref to) => Some(to.clone()),
+      _ => None,
+    };
+
+    let dict = PyDict::new(py);
+    dict.set_item(py, "sender", self.0.sender.clone()).unwrap();
+    dict.set_item(py, "channel", self.0.channel.name()).unwrap();
+    dict.set_item(py, "to", to).unwrap();
+    dict.set_item(py, "text", self.0.text.clone()).unwrap();
+    dict
+  }
+}
@@ -1,16 +1,33 @@
-use conf::ClientConf;
+use chat::{ChatInbox, ChatLine};
+use conf::{ClientConf, ClientConfRetry, ClientConfTls};
+use discovery::{discover_servers, DiscoveredServer};
 use object::Object;
 use asset_loader;
-use cpython::PyResult;
+use cpython::{exc, PyErr, PyResult};
+use hpnet::datagram::{decode as decode_datagram, encode as encode_datagram};
+use hpnet::datagram::{Datagram, Parts, MAX_DATAGRAM_LEN};
+use hpnet::delta::{apply as apply_delta, unit_map, History};
+use hpnet::frame::{decode, read_frame_max, write_message, FrameReader, MAX_FRAME_LEN};
+use hpnet::logging;
+use hpnet::message::{ChatChannel, Command, EntityId, Hello, Message, StateUpdate, Welcome};
+use hpnet::message::{capability_names, peek_version, version_mismatch, CAPABILITIES, CAP_CHAT, PROTOCOL_VERSION};
+use hpnet::stream::Stream;
+use hpnet::tls::{fingerprint, fingerprint_matches};
+use native_tls::TlsConnector;
+use rand;
+use rand::Rng;
+use std::cmp;
 use std::fs::File;
 use std::io;
-use std::io::{BufRead, BufReader, Read};
-use std::net::TcpStream;
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream, UdpSocket};
 use std::{thread, time};
 use toml;
-use std::sync::{Arc, RwLock};
-use env_logger;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, RwLock};
 use window_state::WindowState;
+use world::World;
 use renderer_state::RendererState;
 use backend::create_backend;
 
@@ -51,10 +68,15 @@ py_class!(class Client |_py| {
     Ok(HostilePlanetsClient::client_type())
   }
 
+  // Connect to the server in the client config, and stay connected until
+  // either side hangs up.
   def connect(&self) -> PyResult<i32> {
     let client = self.client(_py);
     _py.allow_threads(|| {
-        client.connect().unwrap();
+        match client.stay_connected(&client.conf_address()) {
+          Ok(reason) => info!("disconnected from server: {}", reason),
+          Err(e) => warn!("lost connection to server: {}", e),
+        }
     });
 
     Ok(0)
@@ -63,12 +85,90 @@ py_class!(class Client |_py| {
   def connect_to(&self, address: &str) -> PyResult<i32> {
     let client = self.client(_py);
     _py.allow_threads(|| {
-        client.connect_to(address).unwrap();
+        match client.stay_connected(address) {
+          Ok(reason) => info!("disconnected from server: {}", reason),
+          Err(e) => warn!("lost connection to server: {}", e),
+        }
     });
     
     Ok(0)
   }
 
+  // Look for servers on the local network for `timeout` seconds, and
+  // return the ones that answered.
+  def discover_servers(&self, timeout: f64) -> PyResult<Vec<DiscoveredServer>> {
+    let client = self.client(_py);
+    let port = client.conf.client.discovery.port as u16;
+    let timeout = time::Duration::from_millis((timeout * 1000.0) as u64);
+
+    match _py.allow_threads(|| discover_servers(port, timeout)) {
+      Ok(servers) => Ok(servers),
+      Err(e) => Err(PyErr::new::<exc::IOError, _>(_py, e.to_string())),
+    }
+  }
+
+  // The optional features in use on the connection to the server, by
+  // name.
+  def get_capabilities(&self) -> PyResult<Vec<String>> {
+    let client = self.client(_py);
+    let caps = *client.capabilities.lock().unwrap();
+
+    Ok(capability_names(caps).iter().map(|name| name.to_string()).collect())
+  }
+
+  // The newest snapshot of the game world the server sent us.
+  def get_world(&self) -> PyResult<World> {
+    let client = self.client(_py);
+    let world = client.world.read().unwrap().clone();
+
+    Ok(world)
+  }
+
+  // Send a chat message to every player.
+  def say(&self, text: &str) -> PyResult<bool> {
+    let client = self.client(_py);
+    match client.say(ChatChannel::Global, text) {
+      Ok(_) => Ok(true),
+      Err(e) => Err(PyErr::new::<exc::IOError, _>(_py, e.to_string())),
+    }
+  }
+
+  // Send a chat message to the players on our team.
+  def say_to_team(&self, text: &str) -> PyResult<bool> {
+    let client = self.client(_py);
+    match client.say(ChatChannel::Team, text) {
+      Ok(_) => Ok(true),
+      Err(e) => Err(PyErr::new::<exc::IOError, _>(_py, e.to_string())),
+    }
+  }
+
+  // Send a chat message to one player.
+  def whisper(&self, name: &str, text: &str) -> PyResult<bool> {
+    let client = self.client(_py);
+    match client.say(ChatChannel::Whisper(name.to_string()), text) {
+      Ok(_) => Ok(true),
+      Err(e) => Err(PyErr::new::<exc::IOError, _>(_py, e.to_string())),
+    }
+  }
+
+  // Order one of our units, by the id in get_world(), to move x and y
+  // steps. The server carries it out over the next ticks.
+  def move_unit(&self, unit: u64, x: i64, y: i64) -> PyResult<bool> {
+    let client = self.client(_py);
+    match client.order_move(unit, x, y) {
+      Ok(_) => Ok(true),
+      Err(e) => Err(PyErr::new::<exc::IOError, _>(_py, e.to_string())),
+    }
+  }
+
+  // Take every chat message that has arrived since the last call.
+  def poll_chat(&self) -> PyResult<Vec<ChatLine>> {
+    let client = self.client(_py);
+    let lines = client.chat.lock().unwrap().drain();
+
+    Ok(lines)
+  }
+
   def get_conf(&self) -> PyResult<ClientConf> {
     let client = self.client(_py);
     let conf = client.conf.clone();
@@ -95,10 +195,36 @@ py_class!(class Client |_py| {
   }
 });
 
+// How long the connection thread waits for data before checking its outbox.
+const POLL_INTERVAL_MS: u64 = 50;
+
+// How long to wait for the server to answer while logging in. While we're
+// in its join queue the server writes to us much more often than this.
+const LOGIN_TIMEOUT_SECS: u64 = 30;
+
+// How often to register our UDP address with the server, while no
+// snapshots are arriving.
+const UDP_REGISTER_INTERVAL_MS: u64 = 1000;
+
+// How many snapshots to keep around for deltas to be applied to.
+const SNAPSHOT_HISTORY_LEN: usize = 32;
+
 pub struct HostilePlanetsClient {
   pub name: String,
   pub conf: ClientConf,
-  pub server_con: Option<TcpStream>,
+  // Outbox for the connection to the server, while there is one. The
+  // thread running handle_server() owns the socket and writes out anything
+  // queued here.
+  pub server_con: Arc<Mutex<Option<Sender<Message>>>>,
+  // Session token the server gave us, so we can rejoin as the same player
+  // after a dropped connection.
+  pub session: Arc<Mutex<Option<String>>>,
+  // The game state from the newest snapshot the server sent us.
+  pub world: Arc<RwLock<World>>,
+  // Chat messages that haven't been collected by a script yet.
+  pub chat: Arc<Mutex<ChatInbox>>,
+  // The optional features negotiated with the server, as CAP_* flags.
+  pub capabilities: Arc<Mutex<u32>>,
   pub objects: Arc<RwLock<Vec<Box<Object>>>>,
 }
 
@@ -119,41 +245,326 @@ pub trait _Client {
     String::from("OpenGL")
   }
 
+  // Name of the player to log in as, if one is configured.
+  fn player_name(&self) -> Option<String>;
+
+  // Password for the player's account on the server, if they have one.
+  fn player_password(&self) -> Option<String>;
+
+  // The session token from the last server we logged in to, if any.
+  fn session(&self) -> Option<String>;
+
+  // How hard to try connecting before giving up.
+  fn retry_conf(&self) -> ClientConfRetry;
+
+  // Whether and how to encrypt the connection.
+  fn tls_conf(&self) -> ClientConfTls;
+
   // Connect to a server by address, such as: "127.0.0.1:8080"
-  fn connect_to(&self, address: &str) -> io::Result<TcpStream> {
+  //
+  // Failed attempts are retried with exponential backoff, up to the limit
+  // in the client config. Being turned away by the server isn't retried.
+  fn connect_to(&self, address: &str) -> io::Result<(Stream, Welcome)> {
+    let retry = self.retry_conf();
+    let mut attempt = 0;
+
+    loop {
+      attempt += 1;
+
+      let e = match self.try_connect_to(address) {
+        Ok(res) => return Ok(res),
+        Err(e) => e,
+      };
+
+      match e.kind() {
+        io::ErrorKind::PermissionDenied | io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => return Err(e),
+        _ => {},
+      }
+
+      if retry.max_attempts > 0 && attempt >= retry.max_attempts {
+        error!("failed connecting to server: {:?} : giving up after {} attempts", e, attempt);
+        return Err(e);
+      }
+
+      let delay = backoff_delay(&retry, attempt);
+      warn!("failed connecting to server: {:?} : retrying in {:?} ...", e, delay);
+      thread::sleep(delay);
+    }
+  }
+
+  // Make a single attempt at connecting and logging in.
+  fn try_connect_to(&self, address: &str) -> io::Result<(Stream, Welcome)> {
+    let name = match self.player_name() {
+      Some(name) => name,
+      None => {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no player configured in the client config"));
+      },
+    };
+
     // Connect to the server.
-    println!("connecting to server: {} ...", address);
-    match TcpStream::connect(address) {
-      Ok(stream) => {
-        println!("connected to server: {:?}", stream);
+    info!("connecting to server: {} ...", address);
+    let stream = TcpStream::connect(address)?;
+    debug!("connected to server: {:?}", stream);
+
+    let mut stream = wrap_stream(&self.tls_conf(), stream, address)?;
+
+    // Log in as the configured player, resuming our last session if we
+    // have one.
+    let hello = Hello {
+      protocol_version: PROTOCOL_VERSION,
+      capabilities: CAPABILITIES,
+      name: name,
+      password: self.player_password(),
+      session: self.session(),
+    };
+    write_message(&mut stream, &Message::Hello(hello))?;
+
+    // Wait for the server to say hello, and print the welcome message. If
+    // the server is full we wait in its queue first, and it keeps telling
+    // us where we are in line.
+    stream.set_read_timeout(Some(time::Duration::from_secs(LOGIN_TIMEOUT_SECS)))?;
+    let welcome = loop {
+      match read_handshake(&mut stream)? {
+        Message::Welcome(welcome) => {
+          if welcome.protocol_version != PROTOCOL_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, version_mismatch(welcome.protocol_version)));
+          }
+
+          info!("server msg: {}: {}", welcome.server_name, welcome.message);
+          info!("using features: {:?}", capability_names(welcome.capabilities));
+          break welcome;
+        },
+
+        Message::Queued { position, length } => {
+          info!("server is full, waiting in line: {} of {}", position, length);
+        },
+
+        Message::Ping(seq) => write_message(&mut stream, &Message::Pong(seq))?,
+
+        Message::Error(e) => {
+          warn!("server refused connection: {}", e);
+          return Err(io::Error::new(io::ErrorKind::PermissionDenied, e.message));
+        },
+
+        Message::Disconnect { reason } => {
+          info!("server closed the connection: {}", reason);
+          return Err(io::Error::new(io::ErrorKind::ConnectionAborted, reason));
+        },
+
+        msg => {
+          warn!("unexpected message from server: {:?}", msg);
+          return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a welcome message"));
+        },
+      }
+    };
+
+    Ok((stream, welcome))
+  }
+}
+
+// Read a message from the server while logging in. If the server speaks
+// another version of the protocol, say so instead of failing to decode its
+// welcome.
+fn read_handshake(stream: &mut Stream) -> io::Result<Message> {
+  let body = read_frame_max(stream, MAX_FRAME_LEN)?;
+
+  decode(&body).map_err(|e| match peek_version(&body) {
+    Some(version) if version != PROTOCOL_VERSION => io::Error::new(io::ErrorKind::InvalidData, version_mismatch(version)),
+    _ => e,
+  })
+}
+
+// Do the TLS handshake with the server if TLS is turned on.
+fn wrap_stream(conf: &ClientConfTls, stream: TcpStream, address: &str) -> io::Result<Stream> {
+  if !conf.enabled {
+    return Ok(Stream::Plain(stream));
+  }
+
+  let mut builder = TlsConnector::builder();
+  if conf.pinned_fingerprint.is_some() {
+    // The pin is checked below, instead of the usual chain of trust.
+    builder.danger_accept_invalid_certs(true);
+    builder.danger_accept_invalid_hostnames(true);
+  } else if conf.accept_invalid_certs {
+    warn!("not checking the server's tls certificate, don't do this in production");
+    builder.danger_accept_invalid_certs(true);
+  }
+  let connector = builder.build().map_err(io::Error::other)?;
+
+  let domain = match conf.server_name {
+    Some(ref server_name) => server_name.clone(),
+    None => host_of(address),
+  };
+
+  let stream = connector
+    .connect(&domain, stream)
+    .map_err(|e| io::Error::other(format!("tls handshake failed: {}", e)))?;
+
+  if let Some(ref pin) = conf.pinned_fingerprint {
+    let der = match stream.peer_certificate() {
+      Ok(Some(cert)) => cert.to_der().map_err(io::Error::other)?,
+      _ => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "server sent no tls certificate")),
+    };
+
+    let fp = fingerprint(&der);
+    if !fingerprint_matches(&fp, pin) {
+      warn!("server tls certificate fingerprint {} doesn't match the pinned one", fp);
+      return Err(io::Error::new(io::ErrorKind::PermissionDenied, "server tls certificate doesn't match the pinned fingerprint"));
+    }
+  }
 
-        // Wait for the server to say hello, and print the welcome message.
-        let mut welcome_msg = String::new();
+  Ok(Stream::Tls(stream))
+}
 
-        let mut reader = BufReader::new(stream.try_clone().unwrap());
-        reader.read_line(&mut welcome_msg).unwrap();
+// The host part of an address like "example.com:8080", "127.0.0.1:8080"
+// or "[::1]:8080", without the port or an IPv6 address's brackets.
+fn host_of(address: &str) -> String {
+  if let Ok(addr) = address.parse::<SocketAddr>() {
+    return addr.ip().to_string();
+  }
 
-        print!("server msg: {}", welcome_msg);
+  match address.rfind(':') {
+    Some(i) => address[..i].to_string(),
+    None => address.to_string(),
+  }
+}
+
+// Service our UDP socket while `active` is set. We keep registering with
+// the server until snapshots start arriving, since the registration can
+// get lost too, and keep the newest snapshot in `world`, once all of its
+// parts have arrived. Every snapshot is acknowledged, so the server can
+// send the next one as a delta against it.
+fn serve_udp(server: SocketAddr, token: u64, world: Arc<RwLock<World>>, active: Arc<AtomicBool>) -> io::Result<()> {
+  let local = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+  let socket = UdpSocket::bind(local)?;
+  socket.connect(server)?;
+  socket.set_read_timeout(Some(time::Duration::from_millis(POLL_INTERVAL_MS)))?;
+
+  let register_interval = time::Duration::from_millis(UDP_REGISTER_INTERVAL_MS);
+  let mut last_register: Option<time::Instant> = None;
+  let mut last_snapshot: Option<time::Instant> = None;
+  let mut buf = vec![0; MAX_DATAGRAM_LEN];
+  let mut history = History::new(SNAPSHOT_HISTORY_LEN);
+  let mut parts = Parts::new();
+
+  while active.load(Ordering::SeqCst) {
+    let receiving = last_snapshot.map_or(false, |t| t.elapsed() < register_interval);
+    let due = last_register.map_or(true, |t| t.elapsed() >= register_interval);
+    if !receiving && due {
+      send_datagram(&socket, &Datagram::Register { token: token });
+      last_register = Some(time::Instant::now());
+    }
 
-        Ok(stream)
+    let datagram = match socket.recv(&mut buf) {
+      Ok(len) => match decode_datagram(&buf[..len]) {
+        Ok(datagram) => datagram,
+        Err(e) => {
+          warn!("bad udp datagram from server: {}", e);
+          continue;
+        },
       },
+      // The server's UDP port isn't open (yet). Keep trying, since the
+      // TCP connection is what decides whether we're connected.
+      Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+      Err(e) => return Err(e),
+    };
 
+    let datagram = match parts.add(datagram) {
+      Ok(Some(datagram)) => datagram,
+      Ok(None) => continue,
       Err(e) => {
-        println!("failed connecting to server: {:?} : retrying in 10 seconds ...", e);
-        thread::sleep(time::Duration::from_millis(1000 * 10));
+        warn!("bad udp datagram from server: {}", e);
+        continue;
+      },
+    };
 
-        self.connect_to(address)
+    let (seq, units) = match datagram {
+      Datagram::Snapshot(snapshot) => (snapshot.seq, unit_map(snapshot.units)),
+
+      Datagram::Delta(delta) => {
+        let units = match history.get(delta.base) {
+          Some(base) => apply_delta(base, &delta.changed, &delta.removed),
+          None => Err(io::Error::new(io::ErrorKind::NotFound, "missing base snapshot")),
+        };
+
+        match units {
+          Ok(units) => (delta.seq, units),
+          Err(e) => {
+            // We can't rebuild the state from this, so ask for the whole
+            // thing instead.
+            info!("can't apply snapshot delta {} against {}: {} : resyncing", delta.seq, delta.base, e);
+            history.clear();
+            send_datagram(&socket, &Datagram::Resync { token: token });
+            continue;
+          },
+        }
       },
-    }
+
+      datagram => {
+        warn!("unexpected udp datagram from server: {:?}", datagram);
+        continue;
+      },
+    };
+
+    last_snapshot = Some(time::Instant::now());
+    world.write().unwrap().apply(seq, &units);
+    history.push(seq, units);
+
+    // Tell the server it can send deltas against this one.
+    send_datagram(&socket, &Datagram::Ack { token: token, seq: seq });
   }
+
+  Ok(())
+}
+
+// Send a datagram to the server. Losing one is normal for UDP, so errors
+// are only logged.
+fn send_datagram(socket: &UdpSocket, datagram: &Datagram) {
+  if let Err(e) = encode_datagram(datagram).and_then(|buf| socket.send(&buf)) {
+    warn!("failed sending udp datagram to server: {}", e);
+  }
+}
+
+// How long to wait before the next connection attempt: the initial
+// backoff, doubled after every failed attempt up to the maximum, then
+// spread out by a random amount so a crowd of clients don't all retry at
+// once.
+fn backoff_delay(retry: &ClientConfRetry, attempt: u32) -> time::Duration {
+  let exp = cmp::min(attempt.saturating_sub(1), 16);
+  let base = cmp::min(retry.initial_backoff_ms.saturating_mul(1 << exp), retry.max_backoff_ms);
+
+  let jitter = retry.jitter.clamp(0.0, 1.0);
+  let spread = 1.0 + jitter * (2.0 * rand::thread_rng().gen::<f64>() - 1.0);
+
+  time::Duration::from_millis((base as f64 * spread) as u64)
 }
 
-impl _Client for HostilePlanetsClient {}
+impl _Client for HostilePlanetsClient {
+  fn player_name(&self) -> Option<String> {
+    self.conf.client.players.first().map(|p| p.name.clone())
+  }
+
+  fn player_password(&self) -> Option<String> {
+    self.conf.client.players.first().and_then(|p| p.password.clone())
+  }
+
+  fn session(&self) -> Option<String> {
+    self.session.lock().unwrap().clone()
+  }
+
+  fn retry_conf(&self) -> ClientConfRetry {
+    self.conf.client.retry.clone()
+  }
+
+  fn tls_conf(&self) -> ClientConfTls {
+    self.conf.client.tls.clone()
+  }
+}
 
 impl HostilePlanetsClient {
   pub fn new(conf_path: &str) -> Self {
     let name = String::from("Hostile Planets client");
-    println!("loading {} ...", name);
 
     let mut f = File::open(conf_path).expect("file not found");
     let mut contents = String::new();
@@ -162,38 +573,208 @@ impl HostilePlanetsClient {
 
     let conf: ClientConf = toml::from_str(&contents).unwrap();
 
-    println!("using config {}: {:?}", conf_path, conf);
+    match logging::init(&conf.client.log) {
+      Ok(_) => {},
+      // Some other logger was set up first, and gets our records instead.
+      Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => warn!("ignoring [client.log], logging is already set up: {}", e),
+      Err(e) => panic!("failed setting up logging: {}", e),
+    }
+
+    info!("loading {} ...", name);
+    debug!("using config {}: {:?}", conf_path, conf);
 
     let c = Self {
       name: name.clone(),
       conf: conf,
-      server_con: None,
+      server_con: Arc::new(Mutex::new(None)),
+      session: Arc::new(Mutex::new(None)),
+      world: Arc::new(RwLock::new(World::default())),
+      chat: Arc::new(Mutex::new(ChatInbox::default())),
+      capabilities: Arc::new(Mutex::new(0)),
       objects: Arc::new(RwLock::new(Vec::new())),
     };
 
-    println!("{} loaded", name);
+    info!("{} loaded", name);
 
     c
   }
 
+  // Address of the server specified in conf.toml.
+  pub fn conf_address(&self) -> String {
+    format!("{}:{}", self.conf.client.ip, self.conf.client.port)
+  }
+
   // Connect to the server specified in conf.toml.
-  pub fn connect(&self) -> io::Result<TcpStream> {
+  pub fn connect(&self) -> io::Result<(Stream, Welcome)> {
     // Connect to the server.
-    let addr = format!("{}:{}", self.conf.client.ip, self.conf.client.port);
+    let addr = self.conf_address();
     self.connect_to(&addr)
   }
 
+  // Connect to a server and stay connected. If the connection drops, we
+  // reconnect and resume our session, so we come back as the same player.
+  // Returns once the server closes the connection on purpose, or we run
+  // out of connection attempts.
+  pub fn stay_connected(&self, address: &str) -> io::Result<String> {
+    loop {
+      let (stream, welcome) = self.connect_to(address)?;
+      *self.session.lock().unwrap() = Some(welcome.session.clone());
+      *self.capabilities.lock().unwrap() = welcome.capabilities;
+
+      match self.handle_server(stream, &welcome) {
+        Ok(reason) => return Ok(reason),
+        Err(e) => warn!("lost connection to server: {} : reconnecting ...", e),
+      }
+    }
+  }
+
+  // Talk to the server until either side hangs up, and return why. Pings
+  // are answered here, and anything queued with send() is written out
+  // between reads.
+  pub fn handle_server(&self, mut stream: Stream, welcome: &Welcome) -> io::Result<String> {
+    let timeout = time::Duration::from_millis(welcome.heartbeat_timeout_ms);
+
+    let (outbox, inbox) = channel();
+    *self.server_con.lock().unwrap() = Some(outbox);
+
+    // Unit snapshots come in over UDP, on a thread of their own.
+    *self.world.write().unwrap() = World::default();
+    let udp_active = Arc::new(AtomicBool::new(true));
+    let udp_thread = match welcome.udp_port {
+      Some(port) => {
+        let server = SocketAddr::new(stream.peer_addr()?.ip(), port);
+        let token = welcome.udp_token;
+        let world = Arc::clone(&self.world);
+        let active = Arc::clone(&udp_active);
+
+        Some(thread::spawn(move || {
+          if let Err(e) = serve_udp(server, token, world, active) {
+            warn!("udp channel to server failed: {}", e);
+          }
+        }))
+      },
+      None => None,
+    };
+
+    stream.set_read_timeout(Some(time::Duration::from_millis(POLL_INTERVAL_MS)))?;
+    let mut reader = FrameReader::new();
+    let mut last_seen = time::Instant::now();
+
+    let res = loop {
+      match self.flush_outbox(&mut stream, &inbox) {
+        Ok(Some(reason)) => break Ok(reason),
+        Ok(None) => {},
+        Err(e) => break Err(e),
+      }
+
+      if last_seen.elapsed() > timeout {
+        break Err(io::Error::new(io::ErrorKind::TimedOut, "server stopped responding"));
+      }
+
+      let msg = match reader.poll(&mut stream) {
+        Ok(Some(msg)) => msg,
+        Ok(None) => continue,
+        Err(e) => break Err(e),
+      };
+      last_seen = time::Instant::now();
+
+      match msg {
+        Message::Ping(seq) => {
+          if let Err(e) = write_message(&mut stream, &Message::Pong(seq)) {
+            break Err(e);
+          }
+        },
+
+        Message::Pong(_) => {},
+
+        Message::Disconnect { reason } => break Ok(reason),
+
+        Message::StateUpdate(StateUpdate::Units(units)) => self.world.write().unwrap().replace(units),
+
+        Message::StateUpdate(StateUpdate::Delta { changed, removed }) => {
+          let mut world = self.world.write().unwrap();
+          match apply_delta(&unit_map(world.units.clone()), &changed, &removed) {
+            Ok(units) => world.replace(units.into_values().collect()),
+            Err(e) => warn!("can't apply state update: {}", e),
+          }
+        },
+
+        Message::Chat(msg) => {
+          info!("chat [{}] {}: {}", msg.channel.name(), msg.sender, msg.text);
+          self.chat.lock().unwrap().push(msg);
+        },
+
+        Message::Error(e) => warn!("server error: {}", e),
+
+        msg => debug!("server msg: {:?}", msg),
+      }
+    };
+
+    *self.server_con.lock().unwrap() = None;
+
+    udp_active.store(false, Ordering::SeqCst);
+    if let Some(udp_thread) = udp_thread {
+      if udp_thread.join().is_err() {
+        error!("udp thread panicked");
+      }
+    }
+
+    res
+  }
+
+  // Queue a message to be sent to the server.
+  pub fn send(&self, msg: Message) -> io::Result<()> {
+    match *self.server_con.lock().unwrap() {
+      Some(ref outbox) => outbox.send(msg).map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "connection to server closed")),
+      None => Err(io::Error::new(io::ErrorKind::NotConnected, "not connected to a server")),
+    }
+  }
+
+  // Send a chat message through the server.
+  pub fn say(&self, channel: ChatChannel, text: &str) -> io::Result<()> {
+    let connected = self.server_con.lock().unwrap().is_some();
+    if connected && *self.capabilities.lock().unwrap() & CAP_CHAT == 0 {
+      return Err(io::Error::other("the server doesn't support chat"));
+    }
+
+    self.send(Message::Say {
+      channel: channel,
+      text: text.to_string(),
+    })
+  }
+
+  // Order one of our units to move. The server reports orders it can't
+  // carry out as events, not back to us.
+  pub fn order_move(&self, unit: EntityId, x: i64, y: i64) -> io::Result<()> {
+    self.send(Message::Command(Command::Move { unit: unit, x: x, y: y }))
+  }
+
+  // Write out everything queued with send(). Returns the reason if we just
+  // told the server we're leaving.
+  fn flush_outbox(&self, stream: &mut Stream, inbox: &Receiver<Message>) -> io::Result<Option<String>> {
+    loop {
+      match inbox.try_recv() {
+        Ok(msg) => {
+          write_message(stream, &msg)?;
+          if let Message::Disconnect { reason } = msg {
+            return Ok(Some(reason));
+          }
+        },
+        Err(TryRecvError::Empty) => return Ok(None),
+        Err(TryRecvError::Disconnected) => return Ok(Some(String::from("connection closed by client"))),
+      }
+    }
+  }
+
   #[cfg(not(any(feature = "vulkan", feature = "dx12", feature = "metal", feature = "gl")))]
   pub fn run(&self) -> io::Result<()> {
     let msg = "You need to enable the native API feature (vulkan/dx12/metal/gl) in order for this program to work.";
-    println!("{}", msg);
+    error!("{}", msg);
     Err(io::Error::from(io::ErrorKind::Other))
   }
 
   #[cfg(any(feature = "vulkan", feature = "dx12", feature = "metal", feature = "gl"))]
   pub fn run(&self) -> io::Result<()> {
-    env_logger::init();
-
     let mut window = WindowState::new();
     let (backend, _instance) = create_backend(&mut window);
 
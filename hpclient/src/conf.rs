@@ -1,5 +1,8 @@
 use cpython::{PyDict, Python, ToPyObject};
+use hpnet::discovery::DEFAULT_DISCOVERY_PORT;
+use hpnet::logging::LogConf;
 use std::clone::Clone;
+use std::fmt;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct ClientConf {
@@ -22,6 +25,14 @@ pub struct ClientConfClient {
   pub ip: String,
   pub port: u32,
   pub players: Vec<ClientConfPlayer>,
+  #[serde(default)]
+  pub retry: ClientConfRetry,
+  #[serde(default)]
+  pub tls: ClientConfTls,
+  #[serde(default)]
+  pub discovery: ClientConfDiscovery,
+  #[serde(default)]
+  pub log: LogConf,
 }
 
 impl ToPyObject for ClientConfClient {
@@ -32,14 +43,32 @@ impl ToPyObject for ClientConfClient {
     dict.set_item(py, "ip", self.ip.clone()).unwrap();
     dict.set_item(py, "port", self.port.clone()).unwrap();
     dict.set_item(py, "players", self.players.clone()).unwrap();
+    dict.set_item(py, "retry", self.retry.clone()).unwrap();
+    dict.set_item(py, "tls", self.tls.clone()).unwrap();
+    dict.set_item(py, "discovery", self.discovery.clone()).unwrap();
+    dict.set_item(py, "log", log_conf_dict(py, &self.log)).unwrap();
 
     dict
   }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Clone)]
 pub struct ClientConfPlayer {
   pub name: String,
+
+  // Only needed if the player has an account on the server. It is left
+  // out of the Python dict and the logs, so it can't be printed by
+  // accident.
+  pub password: Option<String>,
+}
+
+impl fmt::Debug for ClientConfPlayer {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("ClientConfPlayer")
+      .field("name", &self.name)
+      .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+      .finish()
+  }
 }
 
 impl ToPyObject for ClientConfPlayer {
@@ -52,3 +81,120 @@ impl ToPyObject for ClientConfPlayer {
     dict
   }
 }
+
+// How to retry a failed connection to the server. Fields left out of the
+// config keep their defaults.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ClientConfRetry {
+  // Give up after this many attempts. 0 means keep trying forever.
+  pub max_attempts: u32,
+
+  // Wait this long after the first failed attempt, in milliseconds. The
+  // wait doubles after each failed attempt after that.
+  pub initial_backoff_ms: u64,
+
+  // Never wait longer than this between attempts, in milliseconds.
+  pub max_backoff_ms: u64,
+
+  // Randomly shorten or lengthen each wait by up to this fraction of it.
+  pub jitter: f64,
+}
+
+impl Default for ClientConfRetry {
+  fn default() -> Self {
+    ClientConfRetry {
+      max_attempts: 10,
+      initial_backoff_ms: 1000,
+      max_backoff_ms: 30000,
+      jitter: 0.2,
+    }
+  }
+}
+
+impl ToPyObject for ClientConfRetry {
+  type ObjectType = PyDict;
+
+  fn to_py_object(&self, py: Python) -> PyDict {
+    let dict = PyDict::new(py);
+    dict.set_item(py, "max_attempts", self.max_attempts).unwrap();
+    dict.set_item(py, "initial_backoff_ms", self.initial_backoff_ms).unwrap();
+    dict.set_item(py, "max_backoff_ms", self.max_backoff_ms).unwrap();
+    dict.set_item(py, "jitter", self.jitter).unwrap();
+
+    dict
+  }
+}
+
+// Encrypting the connection to the server with TLS. Only works if the
+// server has TLS turned on too.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ClientConfTls {
+  #[serde(default)]
+  pub enabled: bool,
+
+  // Name to check the server's certificate against. Defaults to the ip
+  // we connect to.
+  pub server_name: Option<String>,
+
+  // SHA-256 fingerprint of the server's certificate, as printed by the
+  // server at startup. When this is set, only that exact certificate is
+  // accepted, even if it is self-signed.
+  pub pinned_fingerprint: Option<String>,
+
+  // For development: accept any certificate without checking it.
+  #[serde(default)]
+  pub accept_invalid_certs: bool,
+}
+
+impl ToPyObject for ClientConfTls {
+  type ObjectType = PyDict;
+
+  fn to_py_object(&self, py: Python) -> PyDict {
+    let dict = PyDict::new(py);
+    dict.set_item(py, "enabled", self.enabled).unwrap();
+    dict.set_item(py, "server_name", self.server_name.clone()).unwrap();
+    dict.set_item(py, "pinned_fingerprint", self.pinned_fingerprint.clone()).unwrap();
+    dict.set_item(py, "accept_invalid_certs", self.accept_invalid_certs).unwrap();
+
+    dict
+  }
+}
+
+// Finding servers on the local network.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ClientConfDiscovery {
+  // The port servers listen on for discovery probes.
+  pub port: u32,
+}
+
+impl Default for ClientConfDiscovery {
+  fn default() -> Self {
+    ClientConfDiscovery {
+      port: u32::from(DEFAULT_DISCOVERY_PORT),
+    }
+  }
+}
+
+impl ToPyObject for ClientConfDiscovery {
+  type ObjectType = PyDict;
+
+  fn to_py_object(&self, py: Python) -> PyDict {
+    let dict = PyDict::new(py);
+    dict.set_item(py, "port", self.port).unwrap();
+
+    dict
+  }
+}
+
+// LogConf comes from hpnet, which doesn't know about Python.
+fn log_conf_dict(py: Python, conf: &LogConf) -> PyDict {
+  let dict = PyDict::new(py);
+  dict.set_item(py, "level", conf.level.clone()).unwrap();
+  dict.set_item(py, "modules", conf.modules.clone()).unwrap();
+  dict.set_item(py, "file", conf.file.clone()).unwrap();
+  dict.set_item(py, "json", conf.json).unwrap();
+
+  dict
+}
@@ -0,0 +1,117 @@
+use cpython::{PyDict, Python, ToPyObject};
+use hpnet::discovery::{decode, encode, Discovery, DISCOVERY_MAGIC, MAX_DISCOVERY_LEN};
+use rand;
+use rand::Rng;
+use std::cmp;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+// How often to repeat the probe while waiting for answers, in case one
+// gets lost.
+const PROBE_INTERVAL_MS: u64 = 500;
+
+// A server that answered a discovery probe.
+#[derive(Debug, Clone)]
+pub struct DiscoveredServer {
+  // Where to connect to, with Client.connect_to().
+  pub address: SocketAddr,
+  pub name: String,
+  pub map: String,
+  pub players: u32,
+  pub max_players: u32,
+}
+
+impl ToPyObject for DiscoveredServer {
+  type ObjectType = PyDict;
+
+  fn to_py_object(&self, py: Python) -> PyDict {
+    let dict = PyDict::new(py);
+    dict.set_item(py, "address", self.address.to_string()).unwrap();
+    dict.set_item(py, "name", self.name.clone()).unwrap();
+    dict.set_item(py, "map", self.map.clone()).unwrap();
+    dict.set_item(py, "players", self.players).unwrap();
+    dict.set_item(py, "max_players", self.max_players).unwrap();
+    dict
+  }
+}
+
+// Broadcast discovery probes on the local network for `timeout`, and
+// return every server that answered. A server on this machine may show up
+// twice, under both its loopback and its network address.
+pub fn discover_servers(port: u16, timeout: Duration) -> io::Result<Vec<DiscoveredServer>> {
+  let socket = UdpSocket::bind("0.0.0.0:0")?;
+  socket.set_broadcast(true)?;
+
+  let nonce: u64 = rand::thread_rng().gen();
+  let probe = encode(&Discovery::Probe {
+    magic: DISCOVERY_MAGIC,
+    nonce: nonce,
+  })?;
+
+  // Broadcasts don't always make it to servers on this machine, so ask it
+  // directly too.
+  let targets = [
+    SocketAddr::from(([255, 255, 255, 255], port)),
+    SocketAddr::from(([127, 0, 0, 1], port)),
+  ];
+
+  let mut servers: Vec<DiscoveredServer> = Vec::new();
+  let mut buf = vec![0; MAX_DISCOVERY_LEN];
+  let deadline = Instant::now() + timeout;
+  let mut next_probe = Instant::now();
+
+  loop {
+    let now = Instant::now();
+    if now >= deadline {
+      break;
+    }
+
+    if now >= next_probe {
+      for target in &targets {
+        if let Err(e) = socket.send_to(&probe, target) {
+          warn!("failed sending discovery probe to {}: {}", target, e);
+        }
+      }
+      next_probe += Duration::from_millis(PROBE_INTERVAL_MS);
+    }
+
+    // A zero timeout isn't allowed, since it would mean waiting forever.
+    let until = cmp::min(next_probe, deadline);
+    let now = Instant::now();
+    let wait = if until > now { until - now } else { Duration::from_millis(0) };
+    socket.set_read_timeout(Some(cmp::max(wait, Duration::from_millis(1))))?;
+
+    let (len, from) = match socket.recv_from(&mut buf) {
+      Ok(res) => res,
+      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+        || e.kind() == io::ErrorKind::TimedOut
+        || e.kind() == io::ErrorKind::ConnectionRefused => continue,
+      Err(e) => return Err(e),
+    };
+
+    let announcement = match decode(&buf[..len]) {
+      Ok(Discovery::Announce(announcement)) => announcement,
+      _ => continue,
+    };
+    if announcement.nonce != nonce {
+      continue;
+    }
+
+    let address = SocketAddr::new(from.ip(), announcement.port);
+    if servers.iter().any(|server| server.address == address) {
+      continue;
+    }
+
+    info!("found server {} at {}", announcement.name, address);
+    servers.push(DiscoveredServer {
+      address: address,
+      name: announcement.name,
+      map: announcement.map,
+      players: announcement.players,
+      max_players: announcement.max_players,
+    });
+  }
+
+  Ok(servers)
+}
@@ -16,7 +16,6 @@ extern crate serde_derive;
 extern crate cpython;
 extern crate cgmath;
 extern crate chrono;
-extern crate env_logger;
 extern crate timer;
 extern crate toml;
 
@@ -43,7 +42,10 @@ extern crate gfx_backend_vulkan as back;
 extern crate gfx_hal as hal;
 extern crate glsl_to_spirv;
 extern crate gltf;
+extern crate hpnet;
+extern crate native_tls;
 extern crate image;
+extern crate rand;
 extern crate winit;
 #[macro_use]
 extern crate log;
@@ -53,6 +55,7 @@ pub mod asset_loader;
 pub mod backend;
 pub mod backend_state;
 pub mod buffer_state;
+pub mod chat;
 pub mod client;
 pub mod color;
 pub mod color_range;
@@ -63,6 +66,7 @@ pub mod desc_set_layout;
 pub mod device_state;
 pub mod dimensions;
 pub mod dims;
+pub mod discovery;
 pub mod framebuffer_state;
 pub mod gltf_object;
 pub mod image_state;
@@ -78,6 +82,7 @@ pub mod uniform_matrices_data;
 pub mod vertex;
 pub mod window_state;
 pub mod window_type;
+pub mod world;
 
 // #[cfg(test)]
 // mod tests {
@@ -119,7 +119,7 @@ where
     let image_desc = image_desc.create_desc_set(img_desc_pool.as_mut().unwrap());
     let uniform_desc_built = uniform_desc.create_desc_set(uniform_desc_pool.as_mut().unwrap());
 
-    println!("Memory types: {:?}", backend.adapter.memory_types);
+    debug!("memory types: {:?}", backend.adapter.memory_types);
 
     const IMAGE_LOGO: &'static [u8] = include_bytes!("../../data/images/logo-with-blue-bg.png");
     let img = image::load(Cursor::new(&IMAGE_LOGO[..]), image::PNG)
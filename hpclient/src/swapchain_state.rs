@@ -27,7 +27,7 @@ where
     let (caps, formats, _present_modes) = backend
       .surface
       .compatibility(&device.borrow().physical_device);
-    println!("formats: {:?}", formats);
+    debug!("surface formats: {:?}", formats);
     let format = formats.map_or(f::Format::Rgba8Srgb, |formats| {
       formats
         .iter()
@@ -66,7 +66,7 @@ where
       }
     };
 
-    println!("Surface format: {:?}", format);
+    info!("surface format: {:?}", format);
 
     let swap_config = SwapchainConfig::new()
       .with_color(format)
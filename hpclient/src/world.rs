@@ -0,0 +1,57 @@
+use cpython::{PyDict, Python, ToPyObject};
+use hpnet::delta::UnitMap;
+use hpnet::message::UnitState;
+
+// What the client knows about the game world, from the newest snapshot
+// the server sent us.
+#[derive(Debug, Clone, Default)]
+pub struct World {
+  pub seq: u64,
+  pub units: Vec<UnitState>,
+}
+
+impl World {
+  // Replace what we know with a snapshot, unless it is older than the one
+  // we already have. Returns whether it was used.
+  pub fn apply(&mut self, seq: u64, units: &UnitMap) -> bool {
+    if seq <= self.seq {
+      return false;
+    }
+
+    self.seq = seq;
+    self.units = units.values().cloned().collect();
+
+    true
+  }
+
+  // Replace what we know with the units from a state update. Those come
+  // over TCP when there's no UDP channel, or until it's set up, so they
+  // always arrive in order.
+  pub fn replace(&mut self, units: Vec<UnitState>) {
+    self.seq += 1;
+    self.units = units;
+  }
+}
+
+impl ToPyObject for World {
+  type ObjectType = PyDict;
+
+  fn to_py_object(&self, py: Python) -> PyDict {
+    let units: Vec<PyDict> = self.units.iter().map(|unit| {
+      let dict = PyDict::new(py);
+      dict.set_item(py, "id", unit.id).unwrap();
+      dict.set_item(py, "owner", unit.owner.clone()).unwrap();
+      dict.set_item(py, "type", unit.unit_type.clone()).unwrap();
+      dict.set_item(py, "name", unit.name.clone()).unwrap();
+      dict.set_item(py, "x", unit.x).unwrap();
+      dict.set_item(py, "y", unit.y).unwrap();
+      dict
+    }).collect();
+
+    let dict = PyDict::new(py);
+    dict.set_item(py, "seq", self.seq).unwrap();
+    dict.set_item(py, "units", units).unwrap();
+
+    dict
+  }
+}
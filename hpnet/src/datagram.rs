@@ -0,0 +1,449 @@
+use bincode;
+use message::{EntityId, UnitState};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::mem;
+
+// Largest datagram we send or accept. Bigger snapshots should be split
+// before they get here.
+pub const MAX_DATAGRAM_LEN: usize = 65000;
+
+// Snapshots and deltas are split into parts no longer than this, so each
+// fits in one packet on a typical network and isn't fragmented on the way.
+pub const MAX_PART_LEN: usize = 1200;
+
+// Most parts one snapshot or delta can be split into. More than this means
+// the sender is broken or lying, and isn't worth the memory.
+pub const MAX_PARTS: u32 = 4096;
+
+// How many incomplete snapshots or deltas to hold on to while waiting for
+// the rest of their parts. Older ones have been overtaken by then.
+const PENDING_LEN: usize = 4;
+
+// Messages sent over the UDP channel. Each one fits in a single datagram,
+// and may be lost, duplicated or arrive out of order, so only state that
+// is superseded by the next update belongs here. Everything else goes over
+// TCP as a `Message`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Datagram {
+    // Client -> server: send snapshots to the address this came from. The
+    // token comes from the welcome message, so the server knows which
+    // session the address belongs to.
+    Register { token: u64 },
+
+    // Server -> client: the whole state of the world at one point in time,
+    // or one part of it.
+    Snapshot(Snapshot),
+
+    // Server -> client: the state of the world, as the changes since a
+    // snapshot the client has acknowledged, or one part of them.
+    Delta(Delta),
+
+    // Client -> server: we have the snapshot with this sequence number, so
+    // send deltas against it from now on.
+    Ack { token: u64, seq: u64 },
+
+    // Client -> server: we got a delta against a snapshot we don't have,
+    // so send a whole snapshot next.
+    Resync { token: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    // Increases with every snapshot, so clients can drop ones that arrive
+    // late.
+    pub seq: u64,
+
+    // Which part of the snapshot this is, counting from 0, and how many
+    // there are. See `encode_parts`.
+    pub part: u32,
+    pub parts: u32,
+
+    pub units: Vec<UnitState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Delta {
+    pub seq: u64,
+
+    // The snapshot the changes are against.
+    pub base: u64,
+
+    pub part: u32,
+    pub parts: u32,
+
+    // Units that are new or have changed since the base snapshot.
+    pub changed: Vec<UnitDelta>,
+
+    // Units that were in the base snapshot, but are gone now.
+    pub removed: Vec<EntityId>,
+}
+
+// The fields of a unit that changed. Unchanged fields are left out, and a
+// new unit has every field.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UnitDelta {
+    pub id: EntityId,
+    pub owner: Option<String>,
+    pub unit_type: Option<String>,
+    pub name: Option<String>,
+    pub x: Option<i64>,
+    pub y: Option<i64>,
+}
+
+pub fn encode(datagram: &Datagram) -> io::Result<Vec<u8>> {
+    let buf = bincode::serialize(datagram).map_err(to_io_error)?;
+    if buf.len() > MAX_DATAGRAM_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("datagram too large: {} bytes", buf.len()),
+        ));
+    }
+
+    Ok(buf)
+}
+
+pub fn decode(buf: &[u8]) -> io::Result<Datagram> {
+    bincode::deserialize(buf).map_err(to_io_error)
+}
+
+// Encode a snapshot or delta as as many datagrams as it takes to keep each
+// one under MAX_PART_LEN, numbering the parts so `Parts` can put them back
+// together. A unit too big to share a part still gets one of its own.
+// Other datagrams are encoded whole.
+pub fn encode_parts(datagram: &Datagram) -> io::Result<Vec<Vec<u8>>> {
+    match *datagram {
+        Datagram::Snapshot(ref snapshot) => {
+            let header = Datagram::Snapshot(Snapshot {
+                seq: snapshot.seq,
+                part: MAX_PARTS,
+                parts: MAX_PARTS,
+                units: Vec::new(),
+            });
+            let mut groups = pack(&snapshot.units, encoded_len(&header)?)?;
+            if groups.is_empty() {
+                groups.push(Vec::new());
+            }
+
+            let parts = groups.len() as u32;
+            groups
+                .into_iter()
+                .enumerate()
+                .map(|(part, units)| {
+                    encode(&Datagram::Snapshot(Snapshot {
+                        seq: snapshot.seq,
+                        part: part as u32,
+                        parts,
+                        units,
+                    }))
+                })
+                .collect()
+        }
+
+        Datagram::Delta(ref delta) => {
+            let header = Datagram::Delta(Delta {
+                seq: delta.seq,
+                base: delta.base,
+                part: MAX_PARTS,
+                parts: MAX_PARTS,
+                changed: Vec::new(),
+                removed: Vec::new(),
+            });
+            let header_len = encoded_len(&header)?;
+
+            let mut groups: Vec<(Vec<UnitDelta>, Vec<EntityId>)> = Vec::new();
+            for changed in pack(&delta.changed, header_len)? {
+                groups.push((changed, Vec::new()));
+            }
+            for removed in pack(&delta.removed, header_len)? {
+                groups.push((Vec::new(), removed));
+            }
+            if groups.is_empty() {
+                groups.push((Vec::new(), Vec::new()));
+            }
+
+            let parts = groups.len() as u32;
+            groups
+                .into_iter()
+                .enumerate()
+                .map(|(part, (changed, removed))| {
+                    encode(&Datagram::Delta(Delta {
+                        seq: delta.seq,
+                        base: delta.base,
+                        part: part as u32,
+                        parts,
+                        changed,
+                        removed,
+                    }))
+                })
+                .collect()
+        }
+
+        ref datagram => Ok(vec![encode(datagram)?]),
+    }
+}
+
+// Split items into groups that each encode to MAX_PART_LEN or less, along
+// with a header of header_len bytes.
+fn pack<T: Serialize + Clone>(items: &[T], header_len: u64) -> io::Result<Vec<Vec<T>>> {
+    let mut groups = Vec::new();
+    let mut group = Vec::new();
+    let mut len = header_len;
+
+    for item in items {
+        let item_len = bincode::serialized_size(item).map_err(to_io_error)?;
+        if !group.is_empty() && len + item_len > MAX_PART_LEN as u64 {
+            groups.push(mem::take(&mut group));
+            len = header_len;
+        }
+        group.push(item.clone());
+        len += item_len;
+    }
+
+    if !group.is_empty() {
+        groups.push(group);
+    }
+
+    Ok(groups)
+}
+
+fn encoded_len(datagram: &Datagram) -> io::Result<u64> {
+    bincode::serialized_size(datagram).map_err(to_io_error)
+}
+
+// Puts the parts of snapshots and deltas back together, in whatever order
+// they arrive.
+#[derive(Debug, Default)]
+pub struct Parts {
+    pending: BTreeMap<u64, BTreeMap<u32, Datagram>>,
+}
+
+impl Parts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Take one datagram. Returns the whole snapshot or delta once every
+    // part of it has arrived, and any other datagram right away.
+    pub fn add(&mut self, datagram: Datagram) -> io::Result<Option<Datagram>> {
+        let (seq, part, parts) = match datagram {
+            Datagram::Snapshot(ref snapshot) => (snapshot.seq, snapshot.part, snapshot.parts),
+            Datagram::Delta(ref delta) => (delta.seq, delta.part, delta.parts),
+            _ => return Ok(Some(datagram)),
+        };
+
+        if parts == 0 || parts > MAX_PARTS || part >= parts {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad part {} of {} for snapshot {}", part, parts, seq),
+            ));
+        }
+        if parts == 1 {
+            return Ok(Some(datagram));
+        }
+
+        let complete = {
+            let received = self.pending.entry(seq).or_default();
+            received.insert(part, datagram);
+            received.len() == parts as usize
+        };
+
+        if complete {
+            let received = self.pending.remove(&seq).unwrap_or_default();
+            return merge(received.into_values()).map(Some);
+        }
+
+        while self.pending.len() > PENDING_LEN {
+            let oldest = *self.pending.keys().next().unwrap();
+            self.pending.remove(&oldest);
+        }
+
+        Ok(None)
+    }
+}
+
+// Join the parts of one snapshot or delta, in order.
+fn merge<I: Iterator<Item = Datagram>>(parts: I) -> io::Result<Datagram> {
+    let mut whole: Option<Datagram> = None;
+
+    for part in parts {
+        whole = Some(match (whole, part) {
+            (None, part) => part,
+            (Some(Datagram::Snapshot(mut snapshot)), Datagram::Snapshot(part)) => {
+                snapshot.units.extend(part.units);
+                Datagram::Snapshot(snapshot)
+            }
+            (Some(Datagram::Delta(mut delta)), Datagram::Delta(ref mut part)) if part.base == delta.base => {
+                delta.changed.append(&mut part.changed);
+                delta.removed.append(&mut part.removed);
+                Datagram::Delta(delta)
+            }
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "parts of different snapshots"));
+            }
+        });
+    }
+
+    match whole {
+        Some(Datagram::Snapshot(mut snapshot)) => {
+            snapshot.part = 0;
+            snapshot.parts = 1;
+            Ok(Datagram::Snapshot(snapshot))
+        }
+        Some(Datagram::Delta(mut delta)) => {
+            delta.part = 0;
+            delta.parts = 1;
+            Ok(Datagram::Delta(delta))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "no parts")),
+    }
+}
+
+fn to_io_error(e: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(id: EntityId) -> UnitState {
+        UnitState {
+            id,
+            owner: String::from("Harold"),
+            unit_type: String::from("scout"),
+            name: format!("Scout {}", id),
+            x: id as i64,
+            y: -(id as i64),
+        }
+    }
+
+    fn snapshot(seq: u64, units: usize) -> Datagram {
+        Datagram::Snapshot(Snapshot {
+            seq,
+            part: 0,
+            parts: 1,
+            units: (1..=units as u64).map(unit).collect(),
+        })
+    }
+
+    fn decode_all(bufs: Vec<Vec<u8>>) -> Vec<Datagram> {
+        bufs.iter().map(|buf| decode(buf).unwrap()).collect()
+    }
+
+    #[test]
+    fn big_snapshots_are_split_under_the_part_len() {
+        let bufs = encode_parts(&snapshot(1, 500)).unwrap();
+
+        assert!(bufs.len() > 1);
+        assert!(bufs.iter().all(|buf| buf.len() <= MAX_PART_LEN));
+    }
+
+    #[test]
+    fn parts_reassemble_in_any_order() {
+        let whole = snapshot(1, 500);
+        let mut parts = decode_all(encode_parts(&whole).unwrap());
+        parts.reverse();
+
+        let mut received = Parts::new();
+        let last = parts.pop().unwrap();
+        for part in parts {
+            assert_eq!(received.add(part).unwrap(), None);
+        }
+
+        assert_eq!(received.add(last).unwrap(), Some(whole));
+    }
+
+    #[test]
+    fn a_lost_part_only_holds_up_its_own_snapshot() {
+        let mut received = Parts::new();
+
+        let mut first = decode_all(encode_parts(&snapshot(1, 500)).unwrap());
+        first.remove(1);
+        for part in first {
+            assert_eq!(received.add(part).unwrap(), None);
+        }
+
+        let second = snapshot(2, 500);
+        let mut result = None;
+        for part in decode_all(encode_parts(&second).unwrap()) {
+            result = received.add(part).unwrap();
+        }
+        assert_eq!(result, Some(second));
+    }
+
+    #[test]
+    fn incomplete_snapshots_are_dropped_once_overtaken() {
+        let mut received = Parts::new();
+
+        for seq in 1..=(PENDING_LEN as u64 + 2) {
+            let mut parts = decode_all(encode_parts(&snapshot(seq, 500)).unwrap());
+            parts.pop();
+            for part in parts {
+                received.add(part).unwrap();
+            }
+        }
+
+        assert_eq!(received.pending.len(), PENDING_LEN);
+        assert!(!received.pending.contains_key(&1));
+    }
+
+    #[test]
+    fn split_deltas_reassemble() {
+        let whole = Datagram::Delta(Delta {
+            seq: 5,
+            base: 3,
+            part: 0,
+            parts: 1,
+            changed: (1..=200)
+                .map(|id| UnitDelta {
+                    id,
+                    owner: None,
+                    unit_type: None,
+                    name: Some(format!("Scout {}", id)),
+                    x: Some(1),
+                    y: None,
+                })
+                .collect(),
+            removed: (1000..1400).collect(),
+        });
+
+        let parts = decode_all(encode_parts(&whole).unwrap());
+        assert!(parts.len() > 1);
+
+        let mut received = Parts::new();
+        let mut result = None;
+        for part in parts.into_iter().rev() {
+            result = received.add(part).unwrap();
+        }
+        assert_eq!(result, Some(whole));
+    }
+
+    #[test]
+    fn bad_part_numbers_are_refused() {
+        let mut received = Parts::new();
+        let bad = |part, parts| {
+            Datagram::Snapshot(Snapshot {
+                seq: 1,
+                part,
+                parts,
+                units: Vec::new(),
+            })
+        };
+
+        assert!(received.add(bad(0, 0)).is_err());
+        assert!(received.add(bad(2, 2)).is_err());
+        assert!(received.add(bad(0, MAX_PARTS + 1)).is_err());
+    }
+
+    #[test]
+    fn other_datagrams_pass_straight_through() {
+        let ack = Datagram::Ack { token: 7, seq: 3 };
+        let bufs = encode_parts(&ack).unwrap();
+        assert_eq!(bufs.len(), 1);
+
+        let mut received = Parts::new();
+        assert_eq!(received.add(decode(&bufs[0]).unwrap()).unwrap(), Some(ack));
+    }
+}
@@ -0,0 +1,218 @@
+use datagram::UnitDelta;
+use message::{EntityId, UnitState};
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+
+// Every unit in one snapshot, by id.
+pub type UnitMap = BTreeMap<EntityId, UnitState>;
+
+pub fn unit_map(units: Vec<UnitState>) -> UnitMap {
+    units.into_iter().map(|unit| (unit.id, unit)).collect()
+}
+
+// What changed between two snapshots: the units that are new or have
+// changed, with only their changed fields filled in, and the ids of the
+// units that are gone.
+pub fn diff(base: &UnitMap, current: &UnitMap) -> (Vec<UnitDelta>, Vec<EntityId>) {
+    let mut changed = Vec::new();
+    for (&id, unit) in current {
+        let delta = match base.get(&id) {
+            Some(old) => UnitDelta {
+                id,
+                owner: changed_field(&old.owner, &unit.owner),
+                unit_type: changed_field(&old.unit_type, &unit.unit_type),
+                name: changed_field(&old.name, &unit.name),
+                x: changed_field(&old.x, &unit.x),
+                y: changed_field(&old.y, &unit.y),
+            },
+            None => UnitDelta {
+                id,
+                owner: Some(unit.owner.clone()),
+                unit_type: Some(unit.unit_type.clone()),
+                name: Some(unit.name.clone()),
+                x: Some(unit.x),
+                y: Some(unit.y),
+            },
+        };
+
+        if delta.owner.is_some()
+            || delta.unit_type.is_some()
+            || delta.name.is_some()
+            || delta.x.is_some()
+            || delta.y.is_some()
+        {
+            changed.push(delta);
+        }
+    }
+
+    let removed = base
+        .keys()
+        .filter(|id| !current.contains_key(id))
+        .cloned()
+        .collect();
+
+    (changed, removed)
+}
+
+// Rebuild a snapshot from the one a delta was made against.
+pub fn apply(base: &UnitMap, changed: &[UnitDelta], removed: &[EntityId]) -> io::Result<UnitMap> {
+    let mut units = base.clone();
+
+    for id in removed {
+        units.remove(id);
+    }
+
+    for delta in changed {
+        let unit = match units.get(&delta.id).cloned() {
+            Some(unit) => UnitState {
+                id: delta.id,
+                owner: delta.owner.clone().unwrap_or(unit.owner),
+                unit_type: delta.unit_type.clone().unwrap_or(unit.unit_type),
+                name: delta.name.clone().unwrap_or(unit.name),
+                x: delta.x.unwrap_or(unit.x),
+                y: delta.y.unwrap_or(unit.y),
+            },
+            None => match (&delta.owner, &delta.unit_type, &delta.name, delta.x, delta.y) {
+                (Some(owner), Some(unit_type), Some(name), Some(x), Some(y)) => UnitState {
+                    id: delta.id,
+                    owner: owner.clone(),
+                    unit_type: unit_type.clone(),
+                    name: name.clone(),
+                    x,
+                    y,
+                },
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("delta for unknown unit: {}", delta.id),
+                    ));
+                }
+            },
+        };
+
+        units.insert(delta.id, unit);
+    }
+
+    Ok(units)
+}
+
+fn changed_field<T: Clone + PartialEq>(old: &T, new: &T) -> Option<T> {
+    if old == new {
+        None
+    } else {
+        Some(new.clone())
+    }
+}
+
+// The last few snapshots sent or received, so deltas can be made against,
+// or applied to, whichever one the other side has.
+#[derive(Debug)]
+pub struct History {
+    capacity: usize,
+    snapshots: VecDeque<(u64, UnitMap)>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            capacity,
+            snapshots: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, seq: u64, units: UnitMap) {
+        if self.snapshots.len() >= self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((seq, units));
+    }
+
+    pub fn get(&self, seq: u64) -> Option<&UnitMap> {
+        self.snapshots
+            .iter()
+            .find(|(s, _)| *s == seq)
+            .map(|(_, units)| units)
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(id: EntityId, x: i64, y: i64) -> UnitState {
+        UnitState {
+            id,
+            owner: String::from("Harold"),
+            unit_type: String::from("scout"),
+            name: format!("Scout {}", id),
+            x,
+            y,
+        }
+    }
+
+    #[test]
+    fn diff_then_apply_rebuilds_the_snapshot() {
+        let base = unit_map(vec![unit(1, 0, 0), unit(2, 5, 5), unit(3, 9, 9)]);
+        let current = unit_map(vec![unit(1, 1, 0), unit(3, 9, 9), unit(4, 2, 2)]);
+
+        let (changed, removed) = diff(&base, &current);
+
+        // Only what changed is sent: unit 1's x, and all of the new unit 4.
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed[0].id, 1);
+        assert_eq!(changed[0].x, Some(1));
+        assert_eq!(changed[0].y, None);
+        assert_eq!(changed[0].name, None);
+        assert_eq!(changed[1].id, 4);
+        assert_eq!(changed[1].name, Some(String::from("Scout 4")));
+        assert_eq!(removed, vec![2]);
+
+        assert_eq!(apply(&base, &changed, &removed).unwrap(), current);
+    }
+
+    #[test]
+    fn diff_of_the_same_snapshot_is_empty() {
+        let units = unit_map(vec![unit(1, 0, 0), unit(2, 5, 5)]);
+
+        let (changed, removed) = diff(&units, &units);
+
+        assert!(changed.is_empty());
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn apply_refuses_a_partial_delta_for_an_unknown_unit() {
+        let base = unit_map(vec![unit(1, 0, 0)]);
+        let delta = UnitDelta {
+            id: 7,
+            owner: None,
+            unit_type: None,
+            name: None,
+            x: Some(3),
+            y: None,
+        };
+
+        let e = apply(&base, &[delta], &[]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn history_forgets_the_oldest_snapshots() {
+        let mut history = History::new(2);
+        history.push(1, unit_map(vec![unit(1, 0, 0)]));
+        history.push(2, unit_map(vec![unit(1, 1, 0)]));
+        history.push(3, unit_map(vec![unit(1, 2, 0)]));
+
+        // A delta against snapshot 1 can't be applied any more, so the
+        // client has to ask for a resync.
+        assert!(history.get(1).is_none());
+        assert_eq!(history.get(3).unwrap()[&1].x, 2);
+
+        history.clear();
+        assert!(history.get(3).is_none());
+    }
+}
@@ -0,0 +1,58 @@
+use bincode;
+use std::io;
+
+// Port servers listen on for discovery probes, unless configured otherwise.
+pub const DEFAULT_DISCOVERY_PORT: u16 = 8089;
+
+// Starts every probe, so servers can ignore stray packets from other
+// programs using the same port.
+pub const DISCOVERY_MAGIC: u32 = 0x4850_4c41;
+
+// Largest discovery packet we send or accept.
+pub const MAX_DISCOVERY_LEN: usize = 1024;
+
+// Packets for finding servers on the local network. A client broadcasts a
+// probe, and every server that hears it answers with an announcement sent
+// straight back to the client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Discovery {
+    // Client -> broadcast: any servers out there? The nonce is echoed back,
+    // so answers to an earlier probe can be told apart.
+    Probe { magic: u32, nonce: u64 },
+
+    // Server -> client: here I am.
+    Announce(Announcement),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Announcement {
+    pub nonce: u64,
+    pub name: String,
+    pub map: String,
+    pub players: u32,
+    pub max_players: u32,
+
+    // The TCP port to connect to. The address is wherever the announcement
+    // came from.
+    pub port: u16,
+}
+
+pub fn encode(packet: &Discovery) -> io::Result<Vec<u8>> {
+    let buf = bincode::serialize(packet).map_err(to_io_error)?;
+    if buf.len() > MAX_DISCOVERY_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("discovery packet too large: {} bytes", buf.len()),
+        ));
+    }
+
+    Ok(buf)
+}
+
+pub fn decode(buf: &[u8]) -> io::Result<Discovery> {
+    bincode::deserialize(buf).map_err(to_io_error)
+}
+
+fn to_io_error(e: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,255 @@
+use bincode;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use message::Message;
+use std::io;
+use std::io::{Read, Write};
+
+// Size of the length prefix in front of every frame.
+pub const HEADER_LEN: usize = 4;
+
+// Largest message body we are willing to read, so a bad length prefix
+// can't make us allocate unbounded memory.
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+// Encode a message into a frame: a big-endian u32 body length, followed by
+// the bincode-encoded message.
+pub fn encode(msg: &Message) -> io::Result<Vec<u8>> {
+    let body = bincode::serialize(msg).map_err(to_io_error)?;
+    if body.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("message too large: {} bytes", body.len()),
+        ));
+    }
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+    frame.write_u32::<BigEndian>(body.len() as u32)?;
+    frame.extend_from_slice(&body);
+
+    Ok(frame)
+}
+
+// Decode the body of a frame, without its length prefix.
+pub fn decode(body: &[u8]) -> io::Result<Message> {
+    bincode::deserialize(body).map_err(to_io_error)
+}
+
+// Write one message to a stream.
+pub fn write_message<W: Write>(w: &mut W, msg: &Message) -> io::Result<()> {
+    let frame = encode(msg)?;
+    w.write_all(&frame)?;
+    w.flush()
+}
+
+// Block until one whole message has been read from a stream.
+pub fn read_message<R: Read>(r: &mut R) -> io::Result<Message> {
+    read_message_max(r, MAX_FRAME_LEN)
+}
+
+// Like read_message(), but refuse messages longer than max_len bytes.
+pub fn read_message_max<R: Read>(r: &mut R, max_len: usize) -> io::Result<Message> {
+    let body = read_frame_max(r, max_len)?;
+
+    decode(&body)
+}
+
+// Block until one whole frame has been read from a stream, and return its
+// body without decoding it.
+pub fn read_frame_max<R: Read>(r: &mut R, max_len: usize) -> io::Result<Vec<u8>> {
+    let len = r.read_u32::<BigEndian>()? as usize;
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame too large: {} bytes", len),
+        ));
+    }
+
+    let mut body = vec![0; len];
+    r.read_exact(&mut body)?;
+
+    Ok(body)
+}
+
+// Reassembles messages from a stream that may deliver only part of a frame
+// per read, such as a socket with a read timeout.
+#[derive(Debug)]
+pub struct FrameReader {
+    buf: Vec<u8>,
+    max_len: usize,
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        FrameReader::with_max_len(MAX_FRAME_LEN)
+    }
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        FrameReader::default()
+    }
+
+    // A reader that refuses messages longer than max_len bytes.
+    pub fn with_max_len(max_len: usize) -> Self {
+        FrameReader {
+            buf: Vec::new(),
+            max_len,
+        }
+    }
+
+    // Read whatever is available on the stream, and return the next complete
+    // message if there is one. A read timing out is not an error, it just
+    // means no complete message has arrived yet.
+    pub fn poll<R: Read>(&mut self, r: &mut R) -> io::Result<Option<Message>> {
+        if let Some(msg) = self.next_message()? {
+            return Ok(Some(msg));
+        }
+
+        let mut chunk = [0; 4096];
+        match r.read(&mut chunk) {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed",
+            )),
+            Ok(n) => {
+                self.buf.extend_from_slice(&chunk[..n]);
+                self.next_message()
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Take one complete message off the front of the buffer.
+    fn next_message(&mut self) -> io::Result<Option<Message>> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let len = BigEndian::read_u32(&self.buf[..HEADER_LEN]) as usize;
+        if len > self.max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame too large: {} bytes", len),
+            ));
+        }
+
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let msg = decode(&self.buf[HEADER_LEN..HEADER_LEN + len]);
+        self.buf.drain(..HEADER_LEN + len);
+
+        msg.map(Some)
+    }
+}
+
+fn to_io_error(e: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp;
+
+    // A stream that hands out at most `step` bytes per read, and would
+    // block once it has nothing left.
+    struct Trickle {
+        data: Vec<u8>,
+        pos: usize,
+        step: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos == self.data.len() {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+
+            let n = cmp::min(cmp::min(self.step, buf.len()), self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn poll_puts_together_a_frame_read_a_byte_at_a_time() {
+        let msg = Message::Disconnect {
+            reason: String::from("bye"),
+        };
+        let mut stream = Trickle {
+            data: encode(&msg).unwrap(),
+            pos: 0,
+            step: 1,
+        };
+
+        let mut reader = FrameReader::new();
+        let mut polls = 0;
+        let got = loop {
+            polls += 1;
+            if let Some(got) = reader.poll(&mut stream).unwrap() {
+                break got;
+            }
+        };
+
+        assert_eq!(got, msg);
+        assert!(polls > HEADER_LEN);
+        assert_eq!(reader.poll(&mut stream).unwrap(), None);
+    }
+
+    #[test]
+    fn poll_returns_frames_read_together_one_at_a_time() {
+        let mut data = encode(&Message::Ping(1)).unwrap();
+        data.extend(encode(&Message::Ping(2)).unwrap());
+        let mut stream = Trickle { data, pos: 0, step: 4096 };
+
+        let mut reader = FrameReader::new();
+        assert_eq!(reader.poll(&mut stream).unwrap(), Some(Message::Ping(1)));
+        assert_eq!(reader.poll(&mut stream).unwrap(), Some(Message::Ping(2)));
+        assert_eq!(reader.poll(&mut stream).unwrap(), None);
+    }
+
+    #[test]
+    fn oversize_frames_are_refused() {
+        let msg = Message::Disconnect {
+            reason: "x".repeat(100),
+        };
+        let frame = encode(&msg).unwrap();
+
+        let mut stream = Trickle {
+            data: frame.clone(),
+            pos: 0,
+            step: HEADER_LEN,
+        };
+        let e = FrameReader::with_max_len(10).poll(&mut stream).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let e = read_frame_max(&mut &frame[..], 10).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_closed_stream_is_an_error() {
+        let mut reader = FrameReader::new();
+        let e = reader.poll(&mut &[][..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let msg = Message::Queued { position: 3, length: 7 };
+        let frame = encode(&msg).unwrap();
+
+        assert_eq!(read_message(&mut &frame[..]).unwrap(), msg);
+    }
+}
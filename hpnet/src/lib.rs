@@ -0,0 +1,30 @@
+// Networking code shared by hpserver and hpclient.
+//
+// Everything that goes over the wire between the two crates is a
+// `message::Message`, sent inside a length-prefixed `frame` over a
+// `stream::Stream`, which is optionally encrypted with TLS, or carried over
+// a `websocket` for browser tools. Frequent state
+// updates that can afford to be lost go over UDP instead, as a `datagram`,
+// usually compressed into a `delta` against an earlier update. Servers on
+// the local network can be found with a broadcast `discovery` probe. Both
+// crates set up their `logging` the same way, from their config files.
+#[macro_use]
+extern crate serde_derive;
+extern crate bincode;
+extern crate byteorder;
+extern crate log;
+extern crate native_tls;
+extern crate serde;
+extern crate serde_json;
+extern crate sha2;
+extern crate tungstenite;
+
+pub mod datagram;
+pub mod delta;
+pub mod discovery;
+pub mod frame;
+pub mod logging;
+pub mod message;
+pub mod stream;
+pub mod tls;
+pub mod websocket;
@@ -0,0 +1,268 @@
+// Leveled logging for hpserver and hpclient, set up from their config
+// files. Records go to stderr as text, and optionally to a file as text or
+// as one JSON object per line, for tools that collect logs.
+use log::{self, Level, LevelFilter, Log, Metadata, Record};
+use serde_json;
+use std::cmp::{self, Reverse};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once, RwLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LogConf {
+    // The least important level to log: "error", "warn", "info", "debug",
+    // "trace" or "off".
+    pub level: String,
+
+    // Levels for particular modules, like "hpserver::limiter" = "debug".
+    // The longest matching module path wins, and covers its submodules.
+    pub modules: BTreeMap<String, String>,
+
+    // Also append records to this file.
+    pub file: Option<String>,
+
+    // Write the file as one JSON object per line instead of text.
+    pub json: bool,
+}
+
+impl Default for LogConf {
+    fn default() -> Self {
+        LogConf {
+            level: String::from("info"),
+            modules: BTreeMap::new(),
+            file: None,
+            json: false,
+        }
+    }
+}
+
+// The one logger a process can have. A server and a client in the same
+// process each add their config to it.
+static LOGGER: Logger = Logger {
+    outputs: RwLock::new(Vec::new()),
+};
+
+static INSTALL: Once = Once::new();
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+// Add a config's levels and file to the logger, installing it the first
+// time. Records go to stderr once if any config wants them, and to each
+// config's file if that config wants them. Returns an AlreadyExists error
+// if a logger from somewhere else was installed first.
+pub fn init(conf: &LogConf) -> io::Result<()> {
+    let level = parse_level(&conf.level)?;
+
+    let mut modules = Vec::new();
+    for (module, level) in &conf.modules {
+        modules.push((module.clone(), parse_level(level)?));
+    }
+    // Longest first, so the first match is the most specific one.
+    modules.sort_by_key(|entry| Reverse(entry.0.len()));
+
+    let file = match conf.file {
+        Some(ref path) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        None => None,
+    };
+
+    INSTALL.call_once(|| INSTALLED.store(log::set_logger(&LOGGER).is_ok(), Ordering::SeqCst));
+    if !INSTALLED.load(Ordering::SeqCst) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "another logger is installed"));
+    }
+
+    let mut outputs = LOGGER.outputs.write().unwrap();
+    outputs.push(Output {
+        level,
+        modules,
+        file,
+        json: conf.json,
+    });
+
+    let max_level = outputs.iter().map(Output::max_level).fold(LevelFilter::Off, cmp::max);
+    log::set_max_level(max_level);
+
+    Ok(())
+}
+
+fn parse_level(level: &str) -> io::Result<LevelFilter> {
+    LevelFilter::from_str(level)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("unknown log level: {}", level)))
+}
+
+struct Logger {
+    outputs: RwLock<Vec<Output>>,
+}
+
+// What one config asked to be logged, and where.
+struct Output {
+    level: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    file: Option<Mutex<File>>,
+    json: bool,
+}
+
+impl Output {
+    fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|&(_, level)| level).fold(self.level, cmp::max)
+    }
+
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|entry| {
+                let module = entry.0.as_str();
+                target == module || (target.starts_with(module) && target[module.len()..].starts_with("::"))
+            })
+            .map(|entry| entry.1)
+            .unwrap_or(self.level)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.outputs.read().unwrap().iter().any(|output| output.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        let outputs = self.outputs.read().unwrap();
+        if !outputs.iter().any(|output| output.enabled(record.metadata())) {
+            return;
+        }
+
+        let time = timestamp();
+        let text = format!("{} {:5} {}: {}", time, record.level(), record.target(), record.args());
+        eprintln!("{}", text);
+
+        for output in outputs.iter().filter(|output| output.enabled(record.metadata())) {
+            if let Some(ref file) = output.file {
+                let line = if output.json {
+                    json_line(&time, record)
+                } else {
+                    text.clone()
+                };
+
+                // There's nowhere left to report a failure to write the log.
+                let mut file = file.lock().unwrap();
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for output in self.outputs.read().unwrap().iter() {
+            if let Some(ref file) = output.file {
+                let _ = file.lock().unwrap().flush();
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    time: &'a str,
+    level: &'a str,
+    target: &'a str,
+    thread: Option<&'a str>,
+    file: Option<&'a str>,
+    line: Option<u32>,
+    message: String,
+}
+
+fn json_line(time: &str, record: &Record) -> String {
+    let thread = thread::current();
+    let level = level_name(record.level());
+    let json = JsonRecord {
+        time,
+        level,
+        target: record.target(),
+        thread: thread.name(),
+        file: record.file(),
+        line: record.line(),
+        message: record.args().to_string(),
+    };
+
+    serde_json::to_string(&json).unwrap_or_else(|e| format!("{{\"error\":\"failed encoding log record: {}\"}}", e))
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warn",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+// The current time in UTC, like 2018-06-01T12:30:00.123Z.
+fn timestamp() -> String {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// The date some number of days after 1970-01-01, from Howard Hinnant's
+// date algorithms.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The only test that sets up the logger, since it's global.
+    #[test]
+    fn later_configs_add_to_the_logger() {
+        let server = LogConf {
+            level: String::from("warn"),
+            ..LogConf::default()
+        };
+        init(&server).unwrap();
+
+        let mut modules = BTreeMap::new();
+        modules.insert(String::from("hpclient::client"), String::from("debug"));
+        let client = LogConf {
+            level: String::from("warn"),
+            modules,
+            ..LogConf::default()
+        };
+        init(&client).unwrap();
+
+        assert_eq!(log::max_level(), LevelFilter::Debug);
+        let debug = |target| Metadata::builder().level(Level::Debug).target(target).build();
+        assert!(log::logger().enabled(&debug("hpclient::client")));
+        assert!(!log::logger().enabled(&debug("hpserver::server")));
+    }
+}
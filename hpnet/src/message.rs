@@ -0,0 +1,326 @@
+use byteorder::{ByteOrder, LittleEndian};
+use datagram::UnitDelta;
+use std::fmt;
+
+// Version of the wire protocol spoken by this build. Bump it whenever the
+// encoding of any message changes. Clients and servers only talk to peers
+// that speak the same version.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+// Identifies a unit, or anything else in the game, for as long as the
+// server runs. Ids are never reused.
+pub type EntityId = u64;
+
+// Optional features, as bit flags. Each side lists the features it
+// supports in the handshake, and a feature is only used when both do.
+pub const CAP_UDP_SNAPSHOTS: u32 = 1;
+pub const CAP_DELTA_SNAPSHOTS: u32 = 1 << 1;
+pub const CAP_CHAT: u32 = 1 << 2;
+
+// Every optional feature this build supports.
+pub const CAPABILITIES: u32 = CAP_UDP_SNAPSHOTS | CAP_DELTA_SNAPSHOTS | CAP_CHAT;
+
+// Names of the features in a set of capability flags, for logs and
+// scripts.
+pub fn capability_names(caps: u32) -> Vec<&'static str> {
+    let names = [
+        (CAP_UDP_SNAPSHOTS, "udp_snapshots"),
+        (CAP_DELTA_SNAPSHOTS, "delta_snapshots"),
+        (CAP_CHAT, "chat"),
+    ];
+
+    names
+        .iter()
+        .filter(|&&(cap, _)| caps & cap != 0)
+        .map(|&(_, name)| name)
+        .collect()
+}
+
+// The first three variants keep their place in every version of the
+// protocol, and hello and welcome start with the protocol version, so a
+// peer can always tell which version the other side speaks and refuse it
+// with an error it can read. New variants go at the end.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Message {
+    // Client -> server: the first message on a new connection.
+    Hello(Hello),
+
+    // Server -> client: the handshake was accepted.
+    Welcome(Welcome),
+
+    // Either direction: a request could not be handled.
+    Error(ErrorMsg),
+
+    // Client -> server: instead of a hello, ask for the server's status
+    // without logging in. The server answers with a status message and
+    // closes the connection.
+    QueryStatus,
+
+    // Server -> client: the answer to a status query.
+    Status(ServerStatus),
+
+    // Server -> client: the server is full, and the client is waiting in
+    // line for a free slot. Sent again whenever its place in line changes,
+    // and every so often to show the server is still there. A welcome
+    // follows once the client gets in.
+    Queued { position: u32, length: u32 },
+
+    // Client -> server: something a player wants to do.
+    Command(Command),
+
+    // Client -> server: send a chat message. The server fills in who it is
+    // from.
+    Say { channel: ChatChannel, text: String },
+
+    // Server -> client: a chat message for this player.
+    Chat(ChatMsg),
+
+    // Server -> client: something changed in the game state.
+    StateUpdate(StateUpdate),
+
+    // Either direction: the sender is about to close the connection.
+    Disconnect { reason: String },
+
+    // Either direction: check that the peer is still there. The peer
+    // answers with a pong carrying the same number.
+    Ping(u64),
+    Pong(u64),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hello {
+    // Must stay the first field, see `Message`.
+    pub protocol_version: u32,
+
+    // The optional features the client supports, as CAP_* flags.
+    pub capabilities: u32,
+
+    // The name of the player to log in as.
+    pub name: String,
+
+    // The player's password, if they have an account on the server.
+    pub password: Option<String>,
+
+    // The token from an earlier welcome, to rejoin as the same player after
+    // a dropped connection.
+    pub session: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Welcome {
+    // Must stay the first field, see `Message`.
+    pub protocol_version: u32,
+
+    // The optional features both sides support, which are the ones to use
+    // on this connection.
+    pub capabilities: u32,
+
+    pub server_name: String,
+    pub message: String,
+
+    // The server drops clients that are silent for this long, and the
+    // client should do the same to a silent server.
+    pub heartbeat_timeout_ms: u64,
+
+    // Send this back in the next hello to resume the session.
+    pub session: String,
+
+    // Where the server's UDP socket is, if it has one. Register with it by
+    // sending a `Datagram::Register` with this token.
+    pub udp_port: Option<u16>,
+    pub udp_token: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerStatus {
+    pub name: String,
+    pub protocol_version: u32,
+    pub map: String,
+
+    // The names of the connected players.
+    pub players: Vec<String>,
+    pub max_players: u32,
+    pub uptime_secs: u64,
+    pub tick_rate: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Command {
+    // Move a unit x steps in the x direction, and y steps in the y direction.
+    Move { unit: EntityId, x: i64, y: i64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ChatChannel {
+    // Every player on the server.
+    Global,
+
+    // Every player on the sender's team.
+    Team,
+
+    // Just the named player.
+    Whisper(String),
+}
+
+impl ChatChannel {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ChatChannel::Global => "global",
+            ChatChannel::Team => "team",
+            ChatChannel::Whisper(_) => "whisper",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatMsg {
+    // The name of the player who sent it, or of the server.
+    pub sender: String,
+    pub channel: ChatChannel,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum StateUpdate {
+    // The names of every player on the server.
+    Players(Vec<String>),
+
+    // The current state of a set of units.
+    Units(Vec<UnitState>),
+
+    // The changes to the units since the last update, for clients that
+    // take deltas. Updates over TCP all arrive, so there's nothing to
+    // acknowledge.
+    Delta {
+        changed: Vec<UnitDelta>,
+        removed: Vec<EntityId>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UnitState {
+    pub id: EntityId,
+    pub owner: String,
+    pub unit_type: String,
+    pub name: String,
+    pub x: i64,
+    pub y: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    // The peer sent something that isn't valid protocol, or speaks another
+    // version of it. This must stay first, so peers of any version can
+    // read it.
+    Protocol,
+
+    // The server couldn't handle the request.
+    Internal,
+
+    // The player name is empty or otherwise unusable.
+    InvalidName,
+
+    // Another connected player is already using that name.
+    NameTaken,
+
+    // The player's password was missing or wrong.
+    Auth,
+
+    // The server and its join queue are both full.
+    ServerFull,
+
+    // A chat message couldn't be delivered.
+    Chat,
+
+    // An admin banned the player's name or address.
+    Banned,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorMsg {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl ErrorMsg {
+    pub fn new(kind: ErrorKind, message: &str) -> Self {
+        ErrorMsg {
+            kind,
+            message: String::from(message),
+        }
+    }
+}
+
+impl fmt::Display for ErrorMsg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} error: {}", self.kind, self.message)
+    }
+}
+
+// Read the protocol version out of an encoded hello or welcome, even one
+// from a version of the protocol we can't decode.
+pub fn peek_version(body: &[u8]) -> Option<u32> {
+    if body.len() < 8 {
+        return None;
+    }
+
+    // Bincode writes the variant index, then the first field.
+    match LittleEndian::read_u32(&body[..4]) {
+        0 | 1 => Some(LittleEndian::read_u32(&body[4..8])),
+        _ => None,
+    }
+}
+
+// Why a peer speaking `theirs` can't talk to us.
+pub fn version_mismatch(theirs: u32) -> String {
+    let outdated = if theirs < PROTOCOL_VERSION { "them" } else { "us" };
+    format!(
+        "protocol version mismatch: they speak version {}, we speak version {}; update {}",
+        theirs, PROTOCOL_VERSION, outdated
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode;
+
+    #[test]
+    fn peek_version_reads_hello_and_welcome() {
+        let hello = Message::Hello(Hello {
+            protocol_version: 42,
+            capabilities: CAPABILITIES,
+            name: String::from("Harold"),
+            password: None,
+            session: None,
+        });
+        let welcome = Message::Welcome(Welcome {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: 0,
+            server_name: String::from("server"),
+            message: String::new(),
+            heartbeat_timeout_ms: 1000,
+            session: String::from("token"),
+            udp_port: None,
+            udp_token: 0,
+        });
+
+        assert_eq!(peek_version(&bincode::serialize(&hello).unwrap()), Some(42));
+        assert_eq!(peek_version(&bincode::serialize(&welcome).unwrap()), Some(PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn peek_version_ignores_other_messages() {
+        let error = Message::Error(ErrorMsg::new(ErrorKind::Protocol, "nope"));
+
+        assert_eq!(peek_version(&bincode::serialize(&error).unwrap()), None);
+        assert_eq!(peek_version(&bincode::serialize(&Message::Ping(1)).unwrap()), None);
+        assert_eq!(peek_version(&[0, 0, 0]), None);
+    }
+
+    #[test]
+    fn capability_names_lists_each_flag() {
+        assert_eq!(capability_names(CAP_UDP_SNAPSHOTS | CAP_CHAT), vec!["udp_snapshots", "chat"]);
+        assert!(capability_names(0).is_empty());
+    }
+}
@@ -0,0 +1,82 @@
+use native_tls::TlsStream;
+use websocket::WsStream;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+// A connection between a client and the server, which may be encrypted,
+// and may be a WebSocket carrying either of the other kinds.
+#[derive(Debug)]
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+    WebSocket(Box<WsStream>),
+}
+
+impl Stream {
+    // The socket underneath any encryption.
+    pub fn tcp(&self) -> &TcpStream {
+        match *self {
+            Stream::Plain(ref s) => s,
+            Stream::Tls(ref s) => s.get_ref(),
+            Stream::WebSocket(ref s) => s.get_ref().tcp(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        match *self {
+            Stream::Plain(_) => false,
+            Stream::Tls(_) => true,
+            Stream::WebSocket(ref s) => s.get_ref().is_encrypted(),
+        }
+    }
+
+    pub fn is_websocket(&self) -> bool {
+        matches!(*self, Stream::WebSocket(_))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_read_timeout(dur)
+    }
+
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref s) => s.shutdown(Shutdown::Both),
+            Stream::Tls(ref mut s) => s.shutdown(),
+            Stream::WebSocket(ref mut s) => s.close(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.read(buf),
+            Stream::Tls(ref mut s) => s.read(buf),
+            Stream::WebSocket(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.write(buf),
+            Stream::Tls(ref mut s) => s.write(buf),
+            Stream::WebSocket(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut s) => s.flush(),
+            Stream::Tls(ref mut s) => s.flush(),
+            Stream::WebSocket(ref mut s) => s.flush(),
+        }
+    }
+}
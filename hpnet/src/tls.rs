@@ -0,0 +1,21 @@
+use sha2::{Digest, Sha256};
+
+// The SHA-256 fingerprint of a DER-encoded certificate, as lowercase hex.
+// This is what clients pin, and what the server prints at startup.
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Compare a fingerprint against a pinned one, ignoring case and any colons
+// or spaces the pin was written with.
+pub fn fingerprint_matches(fingerprint: &str, pin: &str) -> bool {
+    let pin: String = pin
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect();
+
+    fingerprint.eq_ignore_ascii_case(&pin)
+}
@@ -0,0 +1,96 @@
+use stream::Stream;
+use tungstenite;
+use tungstenite::{Message as WsMessage, WebSocket};
+use std::io;
+use std::io::{Read, Write};
+
+// A WebSocket connection that reads and writes like a byte stream, so the
+// usual frames can go over it. Every frame is sent as one binary WebSocket
+// message, which lets browser tools speak the same messages as the native
+// client.
+#[derive(Debug)]
+pub struct WsStream {
+    ws: WebSocket<Stream>,
+    // What's left of the last binary message received.
+    incoming: Vec<u8>,
+    read_pos: usize,
+    // Bytes written since the last flush.
+    outgoing: Vec<u8>,
+}
+
+impl WsStream {
+    // Do the server side of the WebSocket handshake.
+    pub fn accept(stream: Stream) -> io::Result<Self> {
+        let ws = tungstenite::accept(stream)
+            .map_err(|e| io::Error::other(format!("websocket handshake failed: {}", e)))?;
+
+        Ok(WsStream {
+            ws,
+            incoming: Vec::new(),
+            read_pos: 0,
+            outgoing: Vec::new(),
+        })
+    }
+
+    // The connection underneath the WebSocket.
+    pub fn get_ref(&self) -> &Stream {
+        self.ws.get_ref()
+    }
+
+    pub fn close(&mut self) -> io::Result<()> {
+        self.ws.close(None).map_err(to_io_error)?;
+        self.ws.write_pending().map_err(to_io_error)
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read_pos >= self.incoming.len() {
+            match self.ws.read_message() {
+                Ok(WsMessage::Binary(data)) => {
+                    self.incoming = data;
+                    self.read_pos = 0;
+                }
+                // Closing looks like the end of the stream.
+                Ok(WsMessage::Close(_)) => return Ok(0),
+                // Pings are answered by tungstenite, and text isn't part of
+                // the protocol.
+                Ok(_) => {}
+                Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => {
+                    return Ok(0)
+                }
+                Err(e) => return Err(to_io_error(e)),
+            }
+        }
+
+        let len = (&self.incoming[self.read_pos..]).read(buf)?;
+        self.read_pos += len;
+
+        Ok(len)
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.outgoing.is_empty() {
+            let data = self.outgoing.split_off(0);
+            self.ws.write_message(WsMessage::Binary(data)).map_err(to_io_error)?;
+        }
+
+        self.ws.write_pending().map_err(to_io_error)
+    }
+}
+
+// Timeouts keep their kind, so readers polling with a read timeout can
+// tell them from real errors.
+fn to_io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
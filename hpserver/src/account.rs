@@ -0,0 +1,79 @@
+extern crate bcrypt;
+extern crate toml;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+
+// Player credentials, kept in the accounts file named in the server config.
+// Only a hash of each password is stored.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct _Accounts {
+    #[serde(default)]
+    pub accounts: BTreeMap<String, _Account>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct _Account {
+    pub password_hash: String,
+}
+
+impl _Accounts {
+    // Load the accounts file, or start with no accounts if there isn't one
+    // yet.
+    pub fn load(path: &str) -> io::Result<Self> {
+        let mut f = match File::open(path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(_Accounts::default());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+
+        toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let contents = toml::to_string(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut f = File::create(path)?;
+        f.write_all(contents.as_bytes())
+    }
+
+    pub fn has_account(&self, name: &str) -> bool {
+        self.accounts.contains_key(name)
+    }
+
+    // Add an account, or replace an existing one.
+    pub fn insert(&mut self, name: &str, account: _Account) {
+        self.accounts.insert(name.to_string(), account);
+    }
+
+    // Remove an account. Returns false if there was no such account.
+    pub fn revoke(&mut self, name: &str) -> bool {
+        self.accounts.remove(name).is_some()
+    }
+}
+
+impl _Account {
+    pub fn new(password: &str) -> Result<Self, String> {
+        if password.is_empty() {
+            return Err(String::from("password can't be empty"));
+        }
+
+        let hash = bcrypt::hash(password, bcrypt::DEFAULT_COST).map_err(|e| e.to_string())?;
+
+        Ok(_Account {
+            password_hash: hash,
+        })
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        bcrypt::verify(password, &self.password_hash).unwrap_or(false)
+    }
+}
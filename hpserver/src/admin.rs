@@ -0,0 +1,160 @@
+use cpython::{PyDict, Python, ToPyObject};
+use std::collections::BTreeMap;
+use std::fmt;
+
+pub const HELP: &str = "commands:
+  list                       list players, their connections and the join queue
+  kick <player> [reason]     disconnect a player
+  ban <player|ip> [reason]   disconnect and ban a player name or address
+  unban <player|ip>          lift a ban
+  bans                       list bans
+  say <text>                 send a chat message to every player
+  reload                     read the config, accounts and ban files again
+  help                       show this
+put names with spaces in double quotes, like: kick \"default player\" afk";
+
+// A command typed at the server console, or sent from a script with
+// Server.admin().
+#[derive(Debug, Clone, PartialEq)]
+pub enum _AdminCommand {
+    List,
+    Kick { name: String, reason: String },
+    Ban { target: String, reason: String },
+    Unban { target: String },
+    Bans,
+    Say { text: String },
+    Reload,
+    Help,
+}
+
+impl _AdminCommand {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let (cmd, rest) = split_arg(line);
+        let cmd = match cmd {
+            Some(cmd) => cmd.to_lowercase(),
+            None => return Err(String::from("no command given, try: help")),
+        };
+
+        match cmd.as_str() {
+            "list" => Ok(_AdminCommand::List),
+            "kick" => {
+                let (name, reason) = split_arg(rest);
+                Ok(_AdminCommand::Kick {
+                    name: name.ok_or("usage: kick <player> [reason]")?,
+                    reason: or_default(reason, "kicked by an admin"),
+                })
+            }
+            "ban" => {
+                let (target, reason) = split_arg(rest);
+                Ok(_AdminCommand::Ban {
+                    target: target.ok_or("usage: ban <player|ip> [reason]")?,
+                    reason: or_default(reason, "banned by an admin"),
+                })
+            }
+            "unban" => {
+                let (target, _) = split_arg(rest);
+                Ok(_AdminCommand::Unban {
+                    target: target.ok_or("usage: unban <player|ip>")?,
+                })
+            }
+            "bans" => Ok(_AdminCommand::Bans),
+            "say" => {
+                if rest.trim().is_empty() {
+                    return Err(String::from("usage: say <text>"));
+                }
+                Ok(_AdminCommand::Say {
+                    text: rest.trim().to_string(),
+                })
+            }
+            "reload" => Ok(_AdminCommand::Reload),
+            "help" => Ok(_AdminCommand::Help),
+            cmd => Err(format!("unknown command: {}, try: help", cmd)),
+        }
+    }
+}
+
+// Split the first argument off a command line. An argument in double
+// quotes can contain spaces.
+fn split_arg(line: &str) -> (Option<String>, &str) {
+    let line = line.trim_start();
+    if line.is_empty() {
+        return (None, line);
+    }
+
+    if line.starts_with('"') {
+        if let Some(end) = line[1..].find('"') {
+            return (Some(line[1..end + 1].to_string()), &line[end + 2..]);
+        }
+    }
+
+    match line.find(char::is_whitespace) {
+        Some(end) => (Some(line[..end].to_string()), &line[end..]),
+        None => (Some(line.to_string()), ""),
+    }
+}
+
+fn or_default(text: &str, default: &str) -> String {
+    let text = text.trim();
+    if text.is_empty() {
+        default.to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+// What an admin command did. Commands that list things, like players or
+// bans, put one row in `rows` per thing.
+#[derive(Debug, Clone, Default)]
+pub struct _AdminResult {
+    pub ok: bool,
+    pub message: String,
+    pub rows: Vec<BTreeMap<String, String>>,
+}
+
+impl _AdminResult {
+    pub fn ok(message: &str) -> Self {
+        _AdminResult {
+            ok: true,
+            message: message.to_string(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn err(message: &str) -> Self {
+        _AdminResult {
+            ok: false,
+            message: message.to_string(),
+            rows: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for _AdminResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.ok {
+            write!(f, "{}", self.message)?;
+        } else {
+            write!(f, "error: {}", self.message)?;
+        }
+
+        for row in &self.rows {
+            let fields: Vec<String> = row.iter().map(|(k, v)| format!("{}={:?}", k, v)).collect();
+            write!(f, "\n  {}", fields.join(" "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ToPyObject for _AdminResult {
+    type ObjectType = PyDict;
+
+    fn to_py_object(&self, py: Python) -> PyDict {
+        let dict = PyDict::new(py);
+        dict.set_item(py, "ok", self.ok).unwrap();
+        dict.set_item(py, "message", self.message.clone()).unwrap();
+        dict.set_item(py, "rows", self.rows.clone()).unwrap();
+
+        dict
+    }
+}
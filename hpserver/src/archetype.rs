@@ -0,0 +1,206 @@
+extern crate toml;
+
+use unit::*;
+
+use hpnet::message::EntityId;
+
+use cpython::{PyDict, Python, ToPyObject};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+// A kind of unit, as designed in a data file. Every unit of the kind
+// shares it.
+#[derive(Deserialize, Debug, Clone)]
+pub struct UnitArchetype {
+    // Taken from the name of the file it was loaded from.
+    #[serde(skip)]
+    pub name: String,
+
+    // Steps a unit can take each tick. Units with no speed can't move.
+    pub speed: u32,
+
+    // Hit points a unit starts with.
+    pub hp: u32,
+
+    // How many steps away a unit can see.
+    pub vision: u32,
+
+    // What it costs to build a unit.
+    pub cost: u32,
+
+    // The model clients draw units of this kind with.
+    pub model: String,
+}
+
+impl ToPyObject for UnitArchetype {
+    type ObjectType = PyDict;
+
+    fn to_py_object(&self, py: Python) -> PyDict {
+        let dict = PyDict::new(py);
+        dict.set_item(py, "name", self.name.clone()).unwrap();
+        dict.set_item(py, "speed", self.speed).unwrap();
+        dict.set_item(py, "hp", self.hp).unwrap();
+        dict.set_item(py, "vision", self.vision).unwrap();
+        dict.set_item(py, "cost", self.cost).unwrap();
+        dict.set_item(py, "model", self.model.clone()).unwrap();
+
+        dict
+    }
+}
+
+// Every kind of unit the server knows about, loaded from the directory
+// named by units_dir in the server config. Designers add a kind of unit by
+// adding a file there.
+#[derive(Debug, Clone, Default)]
+pub struct _UnitRegistry {
+    archetypes: BTreeMap<String, Arc<UnitArchetype>>,
+}
+
+impl _UnitRegistry {
+    // Load every .toml file in dir, one kind of unit per file, named after
+    // the file. A missing directory just means there are no kinds of unit.
+    pub fn load(dir: &str) -> io::Result<Self> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                warn!("no unit types, {} doesn't exist", dir);
+                return Ok(_UnitRegistry::default());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut archetypes = BTreeMap::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("toml") {
+                continue;
+            }
+
+            let archetype = load_archetype(&path)?;
+            debug!("loaded unit type {}: {:?}", archetype.name, archetype);
+            archetypes.insert(archetype.name.clone(), Arc::new(archetype));
+        }
+
+        info!("loaded {} unit types from {}", archetypes.len(), dir);
+
+        Ok(_UnitRegistry { archetypes: archetypes })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<UnitArchetype>> {
+        self.archetypes.get(name)
+    }
+
+    pub fn archetypes(&self) -> BTreeMap<String, UnitArchetype> {
+        self.archetypes
+            .iter()
+            .map(|(name, archetype)| (name.clone(), (**archetype).clone()))
+            .collect()
+    }
+
+    // Make a new unit of a kind, with an id from _Entities. It's up to the
+    // caller to put it somewhere on the map.
+    pub fn spawn(&self, id: EntityId, unit_type: &str, name: &str, x: i64, y: i64) -> Result<Box<Unit>, String> {
+        match self.get(unit_type) {
+            Some(archetype) => Ok(Box::new(ArchetypeUnit::new(id, archetype.clone(), name, x, y))),
+            None => Err(format!("no unit type named {}", unit_type)),
+        }
+    }
+}
+
+fn load_archetype(path: &Path) -> io::Result<UnitArchetype> {
+    let contents = fs::read_to_string(path)?;
+    let mut archetype: UnitArchetype = toml::from_str(&contents)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+    archetype.name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    Ok(archetype)
+}
+
+// A unit that behaves the way its archetype says.
+#[derive(Debug, Clone)]
+pub struct ArchetypeUnit {
+    id: EntityId,
+    archetype: Arc<UnitArchetype>,
+    name: String,
+    hp: u32,
+    x: i64,
+    y: i64,
+    // Steps left to take in each direction.
+    orders: (i64, i64),
+}
+
+impl ArchetypeUnit {
+    pub fn new(id: EntityId, archetype: Arc<UnitArchetype>, name: &str, x: i64, y: i64) -> Self {
+        ArchetypeUnit {
+            id: id,
+            hp: archetype.hp,
+            archetype: archetype,
+            name: String::from(name),
+            x: x,
+            y: y,
+            orders: (0, 0),
+        }
+    }
+}
+
+impl Unit for ArchetypeUnit {
+    fn go(&mut self, x: i64, y: i64, bounds: &MapBounds) -> Result<(i64, i64), String> {
+        let (new_x, new_y) = bounds.step((self.x, self.y), x, y)?;
+        self.x = new_x;
+        self.y = new_y;
+
+        Ok((new_x, new_y))
+    }
+
+    fn order(&mut self, x: i64, y: i64) {
+        self.orders = (x, y);
+    }
+
+    // Take as many steps as the unit's speed allows.
+    fn advance(&mut self, bounds: &MapBounds) -> Result<(), String> {
+        for _ in 0..self.archetype.speed {
+            let (x, y) = (self.orders.0.signum(), self.orders.1.signum());
+            if x == 0 && y == 0 {
+                break;
+            }
+
+            if let Err(e) = self.go(x, y, bounds) {
+                self.orders = (0, 0);
+                return Err(e);
+            }
+            self.orders = (self.orders.0 - x, self.orders.1 - y);
+        }
+
+        Ok(())
+    }
+
+    fn id(&self) -> EntityId {
+        self.id
+    }
+
+    fn unit_type(&self) -> UnitType {
+        UnitType(self.archetype.name.clone())
+    }
+
+    fn hp(&self) -> u32 {
+        self.hp
+    }
+
+    fn pos(&self) -> (i64, i64) {
+        (self.x, self.y)
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn box_clone(&self) -> Box<Unit> {
+        Box::new((*self).clone())
+    }
+}
@@ -0,0 +1,82 @@
+extern crate toml;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+
+// Player names and addresses banned by an admin, kept in the ban file named
+// in the server config. Unlike the rate limiter's temporary bans, these
+// last until an admin lifts them.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct _Bans {
+    #[serde(default)]
+    pub names: BTreeMap<String, _Ban>,
+    #[serde(default)]
+    pub ips: BTreeMap<String, _Ban>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct _Ban {
+    pub reason: String,
+}
+
+impl _Bans {
+    // Load the ban file, or start with no bans if there isn't one yet.
+    pub fn load(path: &str) -> io::Result<Self> {
+        let mut f = match File::open(path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(_Bans::default());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut contents = String::new();
+        f.read_to_string(&mut contents)?;
+
+        toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let contents = toml::to_string(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut f = File::create(path)?;
+        f.write_all(contents.as_bytes())
+    }
+
+    pub fn name_ban(&self, name: &str) -> Option<&_Ban> {
+        self.names.get(name)
+    }
+
+    pub fn ip_ban(&self, ip: IpAddr) -> Option<&_Ban> {
+        self.ips.get(&ip.to_string())
+    }
+
+    pub fn ban_name(&mut self, name: &str, reason: &str) {
+        self.names.insert(name.to_string(), _Ban::new(reason));
+    }
+
+    pub fn ban_ip(&mut self, ip: IpAddr, reason: &str) {
+        self.ips.insert(ip.to_string(), _Ban::new(reason));
+    }
+
+    // Lift a ban. Returns false if there was no such ban.
+    pub fn unban_name(&mut self, name: &str) -> bool {
+        self.names.remove(name).is_some()
+    }
+
+    pub fn unban_ip(&mut self, ip: IpAddr) -> bool {
+        self.ips.remove(&ip.to_string()).is_some()
+    }
+}
+
+impl _Ban {
+    pub fn new(reason: &str) -> Self {
+        _Ban {
+            reason: reason.to_string(),
+        }
+    }
+}
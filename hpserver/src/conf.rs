@@ -1,5 +1,8 @@
 use cpython::{Python, ToPyObject, PyDict};
+use hpnet::discovery::DEFAULT_DISCOVERY_PORT;
+use hpnet::logging::LogConf;
 use std::clone::Clone;
+use std::fmt;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct ServerConf {
@@ -22,6 +25,141 @@ pub struct ServerConfServer {
     pub ip: String,
     pub port: u32,
     pub maps: Vec<ServerConfMap>,
+
+    // How often to ping each client, in milliseconds.
+    #[serde(default = "default_heartbeat_interval_ms")]
+    pub heartbeat_interval_ms: u64,
+
+    // How long a client can go without sending anything before it is
+    // considered disconnected, in milliseconds.
+    #[serde(default = "default_heartbeat_timeout_ms")]
+    pub heartbeat_timeout_ms: u64,
+
+    // How long a disconnected player's session can be resumed for, in
+    // seconds.
+    #[serde(default = "default_session_timeout_secs")]
+    pub session_timeout_secs: u64,
+
+    // Where player accounts are stored.
+    #[serde(default = "default_accounts_file")]
+    pub accounts_file: String,
+
+    // Turn away players who don't have an account. Players who do have an
+    // account always need its password.
+    #[serde(default)]
+    pub require_accounts: bool,
+
+    // Where bans made from the admin console are stored.
+    #[serde(default = "default_bans_file")]
+    pub bans_file: String,
+
+    #[serde(default)]
+    pub tls: ServerConfTls,
+
+    // Where the kinds of unit are defined, one .toml file each.
+    #[serde(default = "default_units_dir")]
+    pub units_dir: String,
+
+    // Where the syllables units are named with are kept, one .toml file
+    // for each faction.
+    #[serde(default = "default_names_dir")]
+    pub names_dir: String,
+
+    // Port for the UDP socket that unit snapshots are sent from. Defaults
+    // to the same number as the TCP port.
+    pub udp_port: Option<u32>,
+
+    // Port to accept WebSocket connections on, for browser tools. They
+    // speak the same messages as the native client, one frame per binary
+    // WebSocket message. Leave it out to turn WebSockets off.
+    pub websocket_port: Option<u32>,
+
+    // How many times per second the game is simulated and its state sent
+    // to clients, from 1 to MAX_TICK_RATE.
+    #[serde(default = "default_tick_rate")]
+    pub tick_rate: u32,
+
+    // Seed for everything random in a game, so it can be replayed from
+    // the same commands. Picked at random if it's left out.
+    pub seed: Option<u64>,
+
+    // Most players that can be connected at once. Players who connect
+    // while the server is full wait in a queue.
+    #[serde(default = "default_max_players")]
+    pub max_players: u32,
+
+    // How many of the player slots are kept free for admins.
+    #[serde(default)]
+    pub reserved_slots: u32,
+
+    // Players who can use the reserved slots and skip the queue. They need
+    // an account, so nobody else can take their name.
+    #[serde(default)]
+    pub admins: Vec<String>,
+
+    // Most players that can wait in the queue.
+    #[serde(default = "default_max_queue")]
+    pub max_queue: u32,
+
+    #[serde(default)]
+    pub limits: ServerConfLimits,
+
+    #[serde(default)]
+    pub chat: ServerConfChat,
+
+    #[serde(default)]
+    pub discovery: ServerConfDiscovery,
+
+    #[serde(default)]
+    pub metrics: ServerConfMetrics,
+
+    // Only read at startup, reloading the config doesn't change it.
+    #[serde(default)]
+    pub log: LogConf,
+}
+
+fn default_max_players() -> u32 {
+    32
+}
+
+fn default_max_queue() -> u32 {
+    16
+}
+
+fn default_heartbeat_interval_ms() -> u64 {
+    2000
+}
+
+fn default_heartbeat_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_session_timeout_secs() -> u64 {
+    300
+}
+
+// Fastest tick_rate allowed. Any faster and a tick would be shorter than
+// a millisecond.
+pub const MAX_TICK_RATE: u32 = 1000;
+
+fn default_tick_rate() -> u32 {
+    20
+}
+
+fn default_accounts_file() -> String {
+    String::from("accounts.toml")
+}
+
+fn default_bans_file() -> String {
+    String::from("bans.toml")
+}
+
+fn default_units_dir() -> String {
+    String::from("data/units")
+}
+
+fn default_names_dir() -> String {
+    String::from("data/names")
 }
 
 impl ToPyObject for ServerConfServer {
@@ -32,6 +170,234 @@ impl ToPyObject for ServerConfServer {
         dict.set_item(py, "ip", self.ip.clone()).unwrap();
         dict.set_item(py, "port", self.port.clone()).unwrap();
         dict.set_item(py, "maps", self.maps.clone()).unwrap();
+        dict.set_item(py, "heartbeat_interval_ms", self.heartbeat_interval_ms).unwrap();
+        dict.set_item(py, "heartbeat_timeout_ms", self.heartbeat_timeout_ms).unwrap();
+        dict.set_item(py, "session_timeout_secs", self.session_timeout_secs).unwrap();
+        dict.set_item(py, "accounts_file", self.accounts_file.clone()).unwrap();
+        dict.set_item(py, "require_accounts", self.require_accounts).unwrap();
+        dict.set_item(py, "bans_file", self.bans_file.clone()).unwrap();
+        dict.set_item(py, "tls", self.tls.clone()).unwrap();
+        dict.set_item(py, "units_dir", self.units_dir.clone()).unwrap();
+        dict.set_item(py, "names_dir", self.names_dir.clone()).unwrap();
+        dict.set_item(py, "udp_port", self.udp_port).unwrap();
+        dict.set_item(py, "websocket_port", self.websocket_port).unwrap();
+        dict.set_item(py, "tick_rate", self.tick_rate).unwrap();
+        dict.set_item(py, "seed", self.seed).unwrap();
+        dict.set_item(py, "max_players", self.max_players).unwrap();
+        dict.set_item(py, "reserved_slots", self.reserved_slots).unwrap();
+        dict.set_item(py, "admins", self.admins.clone()).unwrap();
+        dict.set_item(py, "max_queue", self.max_queue).unwrap();
+        dict.set_item(py, "limits", self.limits.clone()).unwrap();
+        dict.set_item(py, "chat", self.chat.clone()).unwrap();
+        dict.set_item(py, "discovery", self.discovery.clone()).unwrap();
+        dict.set_item(py, "metrics", self.metrics.clone()).unwrap();
+        dict.set_item(py, "log", log_conf_dict(py, &self.log)).unwrap();
+
+        dict
+    }
+}
+
+// Encrypting connections with TLS. Clients must turn on TLS in their
+// config too, or they won't be able to connect.
+#[derive(Deserialize, Clone, Default)]
+pub struct ServerConfTls {
+    #[serde(default)]
+    pub enabled: bool,
+
+    // PEM files with the server's certificate chain and its PKCS#8
+    // private key.
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+
+    // For development: make up a certificate the first time the server
+    // starts, and save it next to the config as selfsigned_cert.pem and
+    // selfsigned_key.pem, instead of reading cert_file and key_file. Its
+    // fingerprint is printed so clients can pin it. Delete the files for a
+    // new one.
+    #[serde(default)]
+    pub self_signed: bool,
+}
+
+// The config is logged when the server starts, so leave out where the
+// private key is kept.
+impl fmt::Debug for ServerConfTls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerConfTls")
+            .field("enabled", &self.enabled)
+            .field("cert_file", &self.cert_file)
+            .field("key_file", &self.key_file.as_ref().map(|_| "<redacted>"))
+            .field("self_signed", &self.self_signed)
+            .finish()
+    }
+}
+
+impl ToPyObject for ServerConfTls {
+    type ObjectType = PyDict;
+
+    fn to_py_object(&self, py: Python) -> PyDict {
+        let dict = PyDict::new(py);
+        dict.set_item(py, "enabled", self.enabled).unwrap();
+        dict.set_item(py, "cert_file", self.cert_file.clone()).unwrap();
+        dict.set_item(py, "key_file", self.key_file.clone()).unwrap();
+        dict.set_item(py, "self_signed", self.self_signed).unwrap();
+
+        dict
+    }
+}
+
+// Limits on how fast clients can connect and send, to protect the server
+// from floods. Clients that break a limit get a strike against their
+// address, and too many strikes get the address banned for a while.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ServerConfLimits {
+    // New connections accepted from one address per minute.
+    pub connections_per_minute: u32,
+
+    // Messages one session can send per second, on average.
+    pub messages_per_second: u32,
+
+    // Messages one session can send in a quick burst.
+    pub message_burst: u32,
+
+    // Largest message a client can send, in bytes.
+    pub max_message_bytes: usize,
+
+    // UDP datagrams accepted from one address per second, on average, and
+    // in a quick burst.
+    pub datagrams_per_second: u32,
+    pub datagram_burst: u32,
+
+    // Strikes it takes to get banned.
+    pub max_strikes: u32,
+
+    // How long a ban lasts, in seconds.
+    pub ban_secs: u64,
+}
+
+impl Default for ServerConfLimits {
+    fn default() -> Self {
+        ServerConfLimits {
+            connections_per_minute: 30,
+            messages_per_second: 50,
+            message_burst: 100,
+            max_message_bytes: 64 * 1024,
+            datagrams_per_second: 100,
+            datagram_burst: 200,
+            max_strikes: 3,
+            ban_secs: 300,
+        }
+    }
+}
+
+impl ToPyObject for ServerConfLimits {
+    type ObjectType = PyDict;
+
+    fn to_py_object(&self, py: Python) -> PyDict {
+        let dict = PyDict::new(py);
+        dict.set_item(py, "connections_per_minute", self.connections_per_minute).unwrap();
+        dict.set_item(py, "messages_per_second", self.messages_per_second).unwrap();
+        dict.set_item(py, "message_burst", self.message_burst).unwrap();
+        dict.set_item(py, "max_message_bytes", self.max_message_bytes).unwrap();
+        dict.set_item(py, "datagrams_per_second", self.datagrams_per_second).unwrap();
+        dict.set_item(py, "datagram_burst", self.datagram_burst).unwrap();
+        dict.set_item(py, "max_strikes", self.max_strikes).unwrap();
+        dict.set_item(py, "ban_secs", self.ban_secs).unwrap();
+
+        dict
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ServerConfChat {
+    // Longest chat message a player can send, in characters.
+    pub max_length: usize,
+
+    // How many global chat messages to keep, to show players who join
+    // later.
+    pub history_len: usize,
+}
+
+impl Default for ServerConfChat {
+    fn default() -> Self {
+        ServerConfChat {
+            max_length: 256,
+            history_len: 50,
+        }
+    }
+}
+
+impl ToPyObject for ServerConfChat {
+    type ObjectType = PyDict;
+
+    fn to_py_object(&self, py: Python) -> PyDict {
+        let dict = PyDict::new(py);
+        dict.set_item(py, "max_length", self.max_length).unwrap();
+        dict.set_item(py, "history_len", self.history_len).unwrap();
+
+        dict
+    }
+}
+
+// Answering clients that look for servers on the local network.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ServerConfDiscovery {
+    pub enabled: bool,
+
+    // The UDP port to listen for discovery probes on, on every interface.
+    pub port: u32,
+}
+
+impl Default for ServerConfDiscovery {
+    fn default() -> Self {
+        ServerConfDiscovery {
+            enabled: true,
+            port: u32::from(DEFAULT_DISCOVERY_PORT),
+        }
+    }
+}
+
+impl ToPyObject for ServerConfDiscovery {
+    type ObjectType = PyDict;
+
+    fn to_py_object(&self, py: Python) -> PyDict {
+        let dict = PyDict::new(py);
+        dict.set_item(py, "enabled", self.enabled).unwrap();
+        dict.set_item(py, "port", self.port).unwrap();
+
+        dict
+    }
+}
+
+// Serving metrics over HTTP for Prometheus to scrape.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ServerConfMetrics {
+    pub enabled: bool,
+
+    // Where to serve them. Keep this on a loopback address unless
+    // everyone who can reach it is allowed to see them.
+    pub address: String,
+}
+
+impl Default for ServerConfMetrics {
+    fn default() -> Self {
+        ServerConfMetrics {
+            enabled: false,
+            address: String::from("127.0.0.1:9108"),
+        }
+    }
+}
+
+impl ToPyObject for ServerConfMetrics {
+    type ObjectType = PyDict;
+
+    fn to_py_object(&self, py: Python) -> PyDict {
+        let dict = PyDict::new(py);
+        dict.set_item(py, "enabled", self.enabled).unwrap();
+        dict.set_item(py, "address", self.address.clone()).unwrap();
 
         dict
     }
@@ -41,6 +407,16 @@ impl ToPyObject for ServerConfServer {
 pub struct ServerConfMap {
     pub name: String,
     pub script: String,
+
+    // Size of the map. Units can't move past its edges.
+    #[serde(default = "default_map_size")]
+    pub width: u32,
+    #[serde(default = "default_map_size")]
+    pub height: u32,
+}
+
+fn default_map_size() -> u32 {
+    256
 }
 
 impl ToPyObject for ServerConfMap {
@@ -50,7 +426,20 @@ impl ToPyObject for ServerConfMap {
         let dict = PyDict::new(py);
         dict.set_item(py, "name", self.name.clone()).unwrap();
         dict.set_item(py, "script", self.script.clone()).unwrap();
+        dict.set_item(py, "width", self.width).unwrap();
+        dict.set_item(py, "height", self.height).unwrap();
         
         dict
     }
 }
+
+// LogConf comes from hpnet, which doesn't know about Python.
+fn log_conf_dict(py: Python, conf: &LogConf) -> PyDict {
+    let dict = PyDict::new(py);
+    dict.set_item(py, "level", conf.level.clone()).unwrap();
+    dict.set_item(py, "modules", conf.modules.clone()).unwrap();
+    dict.set_item(py, "file", conf.file.clone()).unwrap();
+    dict.set_item(py, "json", conf.json).unwrap();
+
+    dict
+}
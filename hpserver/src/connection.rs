@@ -0,0 +1,133 @@
+use hpnet::delta::UnitMap;
+use hpnet::message::Message;
+use rand;
+use rand::Rng;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+// A logged in client. The thread serving the client owns its socket, so
+// everyone else talks to the client by queueing messages in its outbox.
+#[derive(Debug, Clone)]
+pub struct _Connection {
+    // Tells this connection apart from earlier ones by the same player.
+    pub id: usize,
+    pub addr: SocketAddr,
+    pub outbox: Sender<Message>,
+
+    // The client proves which connection its UDP address belongs to by
+    // sending us this token from that address. See _UdpRoutes.
+    pub udp_token: u64,
+
+    // The optional features negotiated with the client, as CAP_* flags.
+    pub capabilities: u32,
+
+    // The newest state of the world, for clients that don't get UDP
+    // snapshots. Each tick replaces it, so a client that can't keep up
+    // skips ahead instead of having states pile up in its outbox.
+    state: Arc<Mutex<Option<Arc<UnitMap>>>>,
+}
+
+impl _Connection {
+    pub fn new(id: usize, addr: SocketAddr, outbox: Sender<Message>, capabilities: u32) -> Self {
+        _Connection {
+            id: id,
+            addr: addr,
+            outbox: outbox,
+            udp_token: rand::thread_rng().gen(),
+            capabilities: capabilities,
+            state: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn set_state(&self, units: Arc<UnitMap>) {
+        *self.state.lock().unwrap() = Some(units);
+    }
+
+    // Take the newest state, if it hasn't been taken yet.
+    pub fn take_state(&self) -> Option<Arc<UnitMap>> {
+        self.state.lock().unwrap().take()
+    }
+
+    // Queue a message for the client. Returns false if the thread serving
+    // the client has already gone away.
+    pub fn send(&self, msg: Message) -> bool {
+        self.outbox.send(msg).is_ok()
+    }
+}
+
+// Where to send each connection's snapshots, by the token its client
+// registers a UDP address with. Kept apart from _Data, like the limiter,
+// so a flood of datagrams never waits on the game state lock.
+#[derive(Debug, Default)]
+pub struct _UdpRoutes {
+    routes: HashMap<u64, _UdpRoute>,
+}
+
+#[derive(Debug, Clone)]
+struct _UdpRoute {
+    con_id: usize,
+    // The address of the TCP connection. Only a UDP address on the same
+    // host can register for it.
+    ip: IpAddr,
+    capabilities: u32,
+    addr: Option<SocketAddr>,
+}
+
+impl _UdpRoutes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Expect a UDP address for a new connection.
+    pub fn add(&mut self, con: &_Connection) {
+        self.routes.insert(
+            con.udp_token,
+            _UdpRoute {
+                con_id: con.id,
+                ip: con.addr.ip(),
+                capabilities: con.capabilities,
+                addr: None,
+            },
+        );
+    }
+
+    // Forget a connection that has closed.
+    pub fn remove(&mut self, con_id: usize) {
+        self.routes.retain(|_, route| route.con_id != con_id);
+    }
+
+    // Send snapshots for the connection with a token to the address it
+    // came from. Returns whether the token was valid from there.
+    pub fn register(&mut self, token: u64, addr: SocketAddr) -> bool {
+        match self.routes.get_mut(&token) {
+            Some(ref mut route) if route.ip == addr.ip() => {
+                if route.addr != Some(addr) {
+                    info!("registered udp address {} for connection {}", addr, route.con_id);
+                }
+                route.addr = Some(addr);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // The id of the connection that registered this token and address.
+    pub fn con_id(&self, token: u64, addr: SocketAddr) -> Option<usize> {
+        match self.routes.get(&token) {
+            Some(route) if route.addr == Some(addr) => Some(route.con_id),
+            _ => None,
+        }
+    }
+
+    // The id, UDP address and capabilities of every connection that has
+    // registered one.
+    pub fn targets(&self) -> Vec<(usize, SocketAddr, u32)> {
+        self.routes
+            .values()
+            .filter_map(|route| route.addr.map(|addr| (route.con_id, addr, route.capabilities)))
+            .collect()
+    }
+}
@@ -0,0 +1,54 @@
+use cpython::{PyDict, Python, ToPyObject};
+use hpnet::message::EntityId;
+use unit::Unit;
+
+// Hands out the ids that commands, snapshots and scripts use to refer to
+// units. Ids start at 1 and are never reused, even after the unit they
+// were given to is gone. Which player owns a unit is found from the
+// players' units, so there's only one place to keep up to date.
+#[derive(Debug, Clone)]
+pub struct _Entities {
+    next_id: EntityId,
+}
+
+impl _Entities {
+    pub fn new() -> Self {
+        _Entities { next_id: 1 }
+    }
+
+    // The id the next call to allocate will take.
+    pub fn peek(&self) -> EntityId {
+        self.next_id
+    }
+
+    // Take the next unused id.
+    pub fn allocate(&mut self) -> EntityId {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+}
+
+impl Default for _Entities {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A unit along with the player that owns it, as scripts look it up by id.
+#[derive(Debug, Clone)]
+pub struct _OwnedUnit {
+    pub owner: String,
+    pub unit: Box<Unit>,
+}
+
+impl ToPyObject for _OwnedUnit {
+    type ObjectType = PyDict;
+
+    fn to_py_object(&self, py: Python) -> PyDict {
+        let dict = self.unit.to_py_object(py);
+        dict.set_item(py, "owner", self.owner.clone()).unwrap();
+
+        dict
+    }
+}
@@ -0,0 +1,72 @@
+use cpython::{PyDict, Python, ToPyObject};
+use hpnet::message::ChatChannel;
+
+// Something that happened on the server which scripts may want to react
+// to. Scripts collect these with Server.poll_events().
+#[derive(Debug, Clone)]
+pub enum _Event {
+    // A player logged in.
+    Connected { player: String },
+
+    // A player's connection was closed or timed out.
+    Disconnected { player: String, reason: String },
+
+    // A player's command couldn't be carried out, or one of their units
+    // had to stop following its orders.
+    CommandFailed { player: String, reason: String },
+
+    // A chat message was delivered.
+    Chat {
+        sender: String,
+        channel: ChatChannel,
+        text: String,
+    },
+}
+
+impl ToPyObject for _Event {
+    type ObjectType = PyDict;
+
+    fn to_py_object(&self, py: Python) -> PyDict {
+        let dict = PyDict::new(py);
+        match *self {
+            _Event::Connected { ref player } => {
+                dict.set_item(py, "type", "connected").unwrap();
+                dict.set_item(py, "player", player.clone()).unwrap();
+            }
+            _Event::Disconnected {
+                ref player,
+                ref reason,
+            } => {
+                dict.set_item(py, "type", "disconnected").unwrap();
+                dict.set_item(py, "player", player.clone()).unwrap();
+                dict.set_item(py, "reason", reason.clone()).unwrap();
+            }
+            _Event::CommandFailed {
+                ref player,
+                ref reason,
+            } => {
+                dict.set_item(py, "type", "command_failed").unwrap();
+                dict.set_item(py, "player", player.clone()).unwrap();
+                dict.set_item(py, "reason", reason.clone()).unwrap();
+            }
+            _Event::Chat {
+                ref sender,
+                ref channel,
+                ref text,
+            } => {
+                let to = match *channel {
+                    ChatChannel::Whisper(ref to) => Some(to.clone()),
+                    _ => None,
+                };
+
+                dict.set_item(py, "type", "chat").unwrap();
+                dict.set_item(py, "sender", sender.clone()).unwrap();
+                dict.set_item(py, "channel", channel.name()).unwrap();
+                dict.set_item(py, "to", to).unwrap();
+                dict.set_item(py, "text", text.clone()).unwrap();
+            }
+        }
+
+        dict
+    }
+}
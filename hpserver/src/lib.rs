@@ -2,8 +2,28 @@
 extern crate serde_derive;
 #[macro_use]
 extern crate cpython;
+extern crate hpnet;
+#[macro_use]
+extern crate log;
+extern crate native_tls;
+extern crate rand;
 
+pub mod account;
+pub mod admin;
+pub mod archetype;
+pub mod ban;
 pub mod conf;
+pub mod connection;
+pub mod entity;
+pub mod event;
+mod limiter;
+pub mod metrics;
+pub mod names;
 pub mod player;
 pub mod server;
+pub mod session;
+pub mod simulation;
+pub mod status;
+mod snapshot;
+mod tls;
 mod unit;
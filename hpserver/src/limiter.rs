@@ -0,0 +1,187 @@
+use conf::ServerConfLimits;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Protects the server from clients that connect or send too fast. Each
+// time a client breaks a limit its address gets a strike, and too many
+// strikes get it banned for a while.
+#[derive(Debug)]
+pub struct _Limiter {
+    conf: ServerConfLimits,
+    hosts: HashMap<IpAddr, _Host>,
+}
+
+#[derive(Debug, Default)]
+struct _Host {
+    connects: VecDeque<Instant>,
+    strikes: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+    datagrams: Option<_MessageBudget>,
+}
+
+// Strikes older than this are forgotten.
+const STRIKE_WINDOW_SECS: u64 = 600;
+
+impl _Limiter {
+    pub fn new(conf: ServerConfLimits) -> Self {
+        _Limiter {
+            conf: conf,
+            hosts: HashMap::new(),
+        }
+    }
+
+    pub fn set_conf(&mut self, conf: ServerConfLimits) {
+        self.conf = conf;
+    }
+
+    // Record a new connection from a host, and check whether to accept it.
+    // Returns why not, if it shouldn't be accepted.
+    pub fn check_connect(&mut self, ip: IpAddr) -> Result<(), String> {
+        if let Some(until) = self.banned_until(ip) {
+            let left = until - Instant::now();
+            return Err(format!("banned for another {} seconds", left.as_secs()));
+        }
+
+        let minute = Duration::from_secs(60);
+        let max = self.conf.connections_per_minute as usize;
+        let over = {
+            let host = self.hosts.entry(ip).or_default();
+            forget_before(&mut host.connects, minute);
+            host.connects.push_back(Instant::now());
+            host.connects.len() > max
+        };
+
+        if over {
+            let reason = format!("more than {} connections per minute", max);
+            self.strike(ip, &reason);
+            return Err(reason);
+        }
+
+        Ok(())
+    }
+
+    // Check whether to handle a UDP datagram from a host. Returns why not,
+    // if it should be dropped. Its source address may be forged, so this
+    // is the only thing we do before checking its token.
+    pub fn check_datagram(&mut self, ip: IpAddr) -> Result<(), String> {
+        if self.banned_until(ip).is_some() {
+            return Err(String::from("banned"));
+        }
+
+        let (rate, burst) = (self.conf.datagrams_per_second, self.conf.datagram_burst);
+        let over = !self
+            .hosts
+            .entry(ip)
+            .or_default()
+            .datagrams
+            .get_or_insert_with(|| _MessageBudget::new(rate, burst))
+            .take();
+
+        if over {
+            let reason = format!("more than {} datagrams per second", rate);
+            self.strike(ip, &reason);
+            return Err(reason);
+        }
+
+        Ok(())
+    }
+
+    // When the host's ban ends, if it is banned.
+    pub fn banned_until(&self, ip: IpAddr) -> Option<Instant> {
+        match self.hosts.get(&ip).and_then(|host| host.banned_until) {
+            Some(until) if until > Instant::now() => Some(until),
+            _ => None,
+        }
+    }
+
+    // Record that a host broke a limit, and ban it if that was one too
+    // many. Returns whether the host is banned now.
+    pub fn strike(&mut self, ip: IpAddr, reason: &str) -> bool {
+        let max_strikes = self.conf.max_strikes as usize;
+        let ban = Duration::from_secs(self.conf.ban_secs);
+
+        let host = self.hosts.entry(ip).or_default();
+        forget_before(&mut host.strikes, Duration::from_secs(STRIKE_WINDOW_SECS));
+        host.strikes.push_back(Instant::now());
+        warn!("rate limit strike {} of {} for {}: {}", host.strikes.len(), max_strikes, ip, reason);
+
+        if host.strikes.len() >= max_strikes {
+            host.strikes.clear();
+            host.banned_until = Some(Instant::now() + ban);
+            warn!("banned {} for {} seconds", ip, ban.as_secs());
+            return true;
+        }
+
+        false
+    }
+
+    // Forget hosts that haven't done anything lately, so the table doesn't
+    // grow forever.
+    pub fn prune(&mut self) {
+        let window = Duration::from_secs(STRIKE_WINDOW_SECS);
+        let now = Instant::now();
+
+        self.hosts.retain(|_, host| {
+            forget_before(&mut host.connects, window);
+            forget_before(&mut host.strikes, window);
+            let banned = match host.banned_until {
+                Some(until) => until > now,
+                None => false,
+            };
+
+            let sending = match host.datagrams {
+                Some(ref budget) => budget.last.elapsed() <= window,
+                None => false,
+            };
+
+            banned || sending || !host.connects.is_empty() || !host.strikes.is_empty()
+        });
+    }
+}
+
+fn forget_before(times: &mut VecDeque<Instant>, age: Duration) {
+    while let Some(&t) = times.front() {
+        if t.elapsed() <= age {
+            break;
+        }
+        times.pop_front();
+    }
+}
+
+// A token bucket limiting how fast one session can send messages. It
+// holds up to `burst` messages, and refills at `rate` messages per second.
+#[derive(Debug)]
+pub struct _MessageBudget {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl _MessageBudget {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        _MessageBudget {
+            rate: f64::from(rate),
+            burst: f64::from(burst),
+            tokens: f64::from(burst),
+            last: Instant::now(),
+        }
+    }
+
+    // Spend one message. Returns false if the session is sending too fast.
+    pub fn take(&mut self) -> bool {
+        let elapsed = self.last.elapsed();
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + secs * self.rate).min(self.burst);
+        self.last = Instant::now();
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
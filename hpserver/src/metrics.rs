@@ -0,0 +1,284 @@
+use cpython::{PyDict, Python, ToPyObject};
+use hpnet::stream::Stream;
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Longest HTTP request head we'll read from a scraper.
+const MAX_REQUEST_LEN: usize = 8192;
+
+// How long a scraper has to send its request.
+const REQUEST_TIMEOUT_SECS: u64 = 1;
+
+// Counters that threads bump as they go. They're atomics rather than part
+// of _Data, so counting never waits on the game state lock, and so that
+// measuring contention on that lock doesn't add to it.
+#[derive(Debug, Default)]
+pub struct _Metrics {
+    bytes_in: AtomicUsize,
+    bytes_out: AtomicUsize,
+    ticks: AtomicUsize,
+    tick_micros_last: AtomicUsize,
+    tick_micros_total: AtomicUsize,
+    lock_contended: AtomicUsize,
+    lock_wait_micros: AtomicUsize,
+}
+
+impl _Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn received(&self, len: usize) {
+        self.bytes_in.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, len: usize) {
+        self.bytes_out.fetch_add(len, Ordering::Relaxed);
+    }
+
+    // Record how long the work of one tick took.
+    pub fn ticked(&self, duration: Duration) {
+        let micros = micros(duration);
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+        self.tick_micros_last.store(micros, Ordering::Relaxed);
+        self.tick_micros_total.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    // Record a wait for a lock that someone else was holding.
+    pub fn lock_waited(&self, duration: Duration) {
+        self.lock_contended.fetch_add(1, Ordering::Relaxed);
+        self.lock_wait_micros.fetch_add(micros(duration), Ordering::Relaxed);
+    }
+
+    // Fill in the counters of a snapshot. The gauges come from the game
+    // state, which the caller has to look at.
+    pub fn snapshot(&self) -> _MetricsSnapshot {
+        _MetricsSnapshot {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            ticks: self.ticks.load(Ordering::Relaxed),
+            tick_seconds_last: seconds(self.tick_micros_last.load(Ordering::Relaxed)),
+            tick_seconds_total: seconds(self.tick_micros_total.load(Ordering::Relaxed)),
+            data_lock_contended: self.lock_contended.load(Ordering::Relaxed),
+            data_lock_wait_seconds: seconds(self.lock_wait_micros.load(Ordering::Relaxed)),
+            ..Default::default()
+        }
+    }
+}
+
+fn micros(duration: Duration) -> usize {
+    duration.as_secs() as usize * 1_000_000 + duration.subsec_micros() as usize
+}
+
+fn seconds(micros: usize) -> f64 {
+    micros as f64 / 1_000_000.0
+}
+
+// The metrics at one moment, as handed to scripts and scrapers.
+#[derive(Debug, Clone, Default)]
+pub struct _MetricsSnapshot {
+    pub players_connected: usize,
+    pub players_queued: usize,
+    // Units in the world, by the name of their type.
+    pub units: BTreeMap<String, usize>,
+    pub ticks: usize,
+    pub tick_seconds_last: f64,
+    pub tick_seconds_total: f64,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub data_lock_contended: usize,
+    pub data_lock_wait_seconds: f64,
+}
+
+impl _MetricsSnapshot {
+    // Render in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        metric(&mut out, "hp_players_connected", "gauge", "Players connected right now.", self.players_connected);
+        metric(&mut out, "hp_players_queued", "gauge", "Players waiting for a free slot.", self.players_queued);
+
+        header(&mut out, "hp_units", "gauge", "Units in the world, by type.");
+        for (unit_type, count) in &self.units {
+            writeln!(out, "hp_units{{type=\"{}\"}} {}", escape_label(unit_type), count).unwrap();
+        }
+
+        metric(&mut out, "hp_ticks_total", "counter", "Ticks run since the server started.", self.ticks);
+        metric(&mut out, "hp_tick_duration_seconds", "gauge", "How long the last tick took.", self.tick_seconds_last);
+        metric(&mut out, "hp_tick_duration_seconds_total", "counter", "Time spent running ticks.", self.tick_seconds_total);
+        metric(&mut out, "hp_received_bytes_total", "counter", "Bytes received from clients, over TCP and UDP.", self.bytes_in);
+        metric(&mut out, "hp_sent_bytes_total", "counter", "Bytes sent to clients, over TCP and UDP.", self.bytes_out);
+        metric(&mut out, "hp_data_lock_contended_total", "counter", "Times a thread had to wait for the game state lock.", self.data_lock_contended);
+        metric(&mut out, "hp_data_lock_wait_seconds_total", "counter", "Time spent waiting for the game state lock.", self.data_lock_wait_seconds);
+
+        out
+    }
+}
+
+// Unit types come from data files, so they could hold anything.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn metric<T: ::std::fmt::Display>(out: &mut String, name: &str, kind: &str, help: &str, value: T) {
+    header(out, name, kind, help);
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+impl ToPyObject for _MetricsSnapshot {
+    type ObjectType = PyDict;
+
+    fn to_py_object(&self, py: Python) -> PyDict {
+        let dict = PyDict::new(py);
+        dict.set_item(py, "players_connected", self.players_connected).unwrap();
+        dict.set_item(py, "players_queued", self.players_queued).unwrap();
+        dict.set_item(py, "units", self.units.clone()).unwrap();
+        dict.set_item(py, "ticks", self.ticks).unwrap();
+        dict.set_item(py, "tick_seconds_last", self.tick_seconds_last).unwrap();
+        dict.set_item(py, "tick_seconds_total", self.tick_seconds_total).unwrap();
+        dict.set_item(py, "bytes_in", self.bytes_in).unwrap();
+        dict.set_item(py, "bytes_out", self.bytes_out).unwrap();
+        dict.set_item(py, "data_lock_contended", self.data_lock_contended).unwrap();
+        dict.set_item(py, "data_lock_wait_seconds", self.data_lock_wait_seconds).unwrap();
+
+        dict
+    }
+}
+
+// A client connection that counts the bytes going through it. Everything
+// else goes straight to the stream underneath.
+#[derive(Debug)]
+pub struct _MeteredStream {
+    stream: Stream,
+    metrics: Arc<_Metrics>,
+}
+
+impl _MeteredStream {
+    pub fn new(stream: Stream, metrics: Arc<_Metrics>) -> Self {
+        _MeteredStream {
+            stream: stream,
+            metrics: metrics,
+        }
+    }
+}
+
+impl Deref for _MeteredStream {
+    type Target = Stream;
+
+    fn deref(&self) -> &Stream {
+        &self.stream
+    }
+}
+
+impl DerefMut for _MeteredStream {
+    fn deref_mut(&mut self) -> &mut Stream {
+        &mut self.stream
+    }
+}
+
+impl Read for _MeteredStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stream.read(buf)?;
+        self.metrics.received(len);
+        Ok(len)
+    }
+}
+
+impl Write for _MeteredStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.stream.write(buf)?;
+        self.metrics.sent(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// Answer one HTTP request from a scraper. Only the metrics page is served,
+// at /metrics or /, and render is only called for it.
+pub fn answer_scrape<F>(stream: &mut TcpStream, render: F) -> io::Result<()>
+where
+    F: FnOnce() -> String,
+{
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+
+    // The whole request has to arrive in time, so a client trickling it in
+    // can't hold up everyone else's scrapes.
+    let deadline = Instant::now() + Duration::from_secs(REQUEST_TIMEOUT_SECS);
+
+    // We don't care about the headers, but read up to the end of them so
+    // the scraper isn't cut off while it's still sending.
+    let mut head = Vec::new();
+    let mut buf = [0; 512];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() >= MAX_REQUEST_LEN {
+            return respond(stream, "431 Request Header Fields Too Large", "");
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request took too long"));
+        }
+        stream.set_read_timeout(Some(deadline - now))?;
+
+        let len = stream.read(&mut buf)?;
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "request ended early"));
+        }
+        head.extend_from_slice(&buf[..len]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("");
+
+    if method != "GET" {
+        respond(stream, "405 Method Not Allowed", "")
+    } else if path != "/metrics" && path != "/" {
+        respond(stream, "404 Not Found", "")
+    } else {
+        respond(stream, "200 OK", &render())
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_types_are_escaped_in_labels() {
+        let mut units = BTreeMap::new();
+        units.insert(String::from("bad\"type\\\n"), 2);
+        let snapshot = _MetricsSnapshot {
+            units: units,
+            ..Default::default()
+        };
+
+        assert!(snapshot.to_prometheus().contains("hp_units{type=\"bad\\\"type\\\\\\n\"} 2\n"));
+    }
+}
@@ -0,0 +1,196 @@
+extern crate toml;
+
+use rand::prng::XorShiftRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// The faction whose syllables are used for players without a faction, or
+// with one that has no file of its own.
+pub const DEFAULT_FACTION: &str = "default";
+
+// Random names to try before numbering one to make it unique.
+const MAX_ATTEMPTS: usize = 16;
+
+// Syllables to build names from: a start, then sometimes a middle, then an
+// end.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Syllables {
+    pub start: Vec<String>,
+    pub middle: Vec<String>,
+    pub end: Vec<String>,
+}
+
+// How the units of one faction are named, as loaded from a data file.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct FactionNames {
+    pub syllables: Syllables,
+
+    // Syllables for particular unit types. A list that's left out or empty
+    // is taken from the faction's.
+    pub units: BTreeMap<String, Syllables>,
+}
+
+impl FactionNames {
+    fn syllables(&self, unit_type: &str) -> Syllables {
+        let faction = &self.syllables;
+        match self.units.get(unit_type) {
+            Some(unit) => Syllables {
+                start: or_else(&unit.start, &faction.start),
+                middle: or_else(&unit.middle, &faction.middle),
+                end: or_else(&unit.end, &faction.end),
+            },
+            None => faction.clone(),
+        }
+    }
+}
+
+fn or_else(list: &[String], fallback: &[String]) -> Vec<String> {
+    if list.is_empty() {
+        fallback.to_vec()
+    } else {
+        list.to_vec()
+    }
+}
+
+// Makes up names for units, like callsigns for chat and the UI. Each name
+// comes from the game's seed, the player and how many names the player has
+// used, so a game replayed with the same seed names its units the same
+// way, whatever order players' units are spawned in.
+#[derive(Debug, Clone)]
+pub struct _NameGenerator {
+    factions: BTreeMap<String, FactionNames>,
+    seed: u64,
+    // How many made up names each player has used.
+    used: BTreeMap<String, u64>,
+}
+
+impl _NameGenerator {
+    pub fn new(factions: BTreeMap<String, FactionNames>, seed: u64) -> Self {
+        _NameGenerator {
+            factions: factions,
+            seed: seed,
+            used: BTreeMap::new(),
+        }
+    }
+
+    // Swap in syllables loaded again from the data files.
+    pub fn set_factions(&mut self, factions: BTreeMap<String, FactionNames>) {
+        self.factions = factions;
+    }
+
+    // The next name for one of a player's units of some type, that taken
+    // says is free. When the random names run out, the last one is
+    // numbered until it's free, so this always returns a name nobody has.
+    // The same name comes back until it's used.
+    pub fn generate<F>(&self, player: &str, faction: Option<&str>, unit_type: &str, taken: F) -> String
+    where
+        F: Fn(&str) -> bool,
+    {
+        let count = self.used.get(player).cloned().unwrap_or(0);
+        let mut rng = XorShiftRng::seed_from_u64(name_seed(self.seed, player, count));
+
+        let syllables = self
+            .factions
+            .get(faction.unwrap_or(DEFAULT_FACTION))
+            .or_else(|| self.factions.get(DEFAULT_FACTION))
+            .map(|names| names.syllables(unit_type))
+            .unwrap_or_default();
+
+        let mut name = String::new();
+        for _ in 0..MAX_ATTEMPTS {
+            name = build(&mut rng, &syllables).unwrap_or_else(|| capitalize(unit_type));
+            if !taken(&name) {
+                return name;
+            }
+        }
+
+        (2..)
+            .map(|n| format!("{} {}", name, n))
+            .find(|numbered| !taken(numbered))
+            .unwrap()
+    }
+
+    // Move a player on to their next name, once the one generate gave has
+    // been given to a unit.
+    pub fn use_name(&mut self, player: &str) {
+        *self.used.entry(String::from(player)).or_insert(0) += 1;
+    }
+}
+
+// Mix the game's seed, a player's name and their count of used names into
+// the seed for that name. FNV-1a, so it's the same on every build.
+fn name_seed(seed: u64, player: &str, count: u64) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in seed.to_le_bytes().iter().chain(player.as_bytes()).chain(count.to_le_bytes().iter()) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash
+}
+
+// A random name, or None if there are no syllables to build one from.
+fn build(rng: &mut XorShiftRng, syllables: &Syllables) -> Option<String> {
+    if syllables.start.is_empty() || syllables.end.is_empty() {
+        return None;
+    }
+
+    let mut name = rng.choose(&syllables.start)?.clone();
+    if rng.gen() {
+        if let Some(middle) = rng.choose(&syllables.middle) {
+            name.push_str(middle);
+        }
+    }
+    name.push_str(rng.choose(&syllables.end)?);
+
+    Some(capitalize(&name))
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+// Load every .toml file in dir, one faction per file, named after the
+// file. A missing directory just means units are named after their type.
+pub fn load_factions(dir: &str) -> io::Result<BTreeMap<String, FactionNames>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("no unit name syllables, {} doesn't exist", dir);
+            return Ok(BTreeMap::new());
+        }
+        Err(e) => return Err(e),
+    };
+
+    let mut factions = BTreeMap::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("toml") {
+            continue;
+        }
+
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        factions.insert(name, load_faction(&path)?);
+    }
+
+    info!("loaded unit names for {} factions from {}", factions.len(), dir);
+
+    Ok(factions)
+}
+
+fn load_faction(path: &Path) -> io::Result<FactionNames> {
+    let contents = fs::read_to_string(path)?;
+    toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
@@ -1,5 +1,6 @@
 use unit::*;
 
+use hpnet::message::{EntityId, UnitState};
 use cpython::{Python, ToPyObject, PyDict};
 use std::clone::Clone;
 
@@ -7,6 +8,10 @@ use std::clone::Clone;
 pub struct _Player {
   pub name:   String,
   pub units:  Units,
+  // Players on the same team can chat among themselves.
+  pub team:   Option<String>,
+  // Which syllables the player's units are named with. See _NameGenerator.
+  pub faction: Option<String>,
 }
 
 impl _Player {
@@ -14,8 +19,74 @@ impl _Player {
     _Player {
       name: String::from(name),
       units: units,
+      team: None,
+      faction: None,
     }
   }
+
+  // The state of each of the player's units, as sent to clients.
+  pub fn unit_states(&self) -> Vec<UnitState> {
+    self.units.values().map(|unit| {
+      let (x, y) = unit.pos();
+      UnitState {
+        id: unit.id(),
+        owner: self.name.clone(),
+        unit_type: String::from(unit.unit_type().name()),
+        name: unit.name(),
+        x: x,
+        y: y,
+      }
+    }).collect()
+  }
+
+  // Give the player a new unit. Units are told apart by id, but each of a
+  // player's units still gets its own name, so players can too.
+  pub fn add_unit(&mut self, unit: Box<Unit>) -> Result<(), String> {
+    if self.has_unit_named(&unit.name()) {
+      return Err(format!("player {} already has a unit named {}", self.name, unit.name()));
+    }
+    if self.units.contains_key(&unit.id()) {
+      return Err(format!("player {} already has unit {}", self.name, unit.id()));
+    }
+
+    self.units.insert(unit.id(), unit);
+    Ok(())
+  }
+
+  // Move one of the player's units right away. See Unit::go.
+  pub fn move_unit(&mut self, id: EntityId, x: i64, y: i64, bounds: &MapBounds) -> Result<(i64, i64), String> {
+    self.unit_mut(id)?.go(x, y, bounds)
+  }
+
+  // Order one of the player's units to move over the next ticks. See
+  // Unit::order.
+  pub fn order_unit(&mut self, id: EntityId, x: i64, y: i64) -> Result<(), String> {
+    self.unit_mut(id)?.order(x, y);
+    Ok(())
+  }
+
+  // Move every unit one step along its orders, in order of their ids.
+  // Returns the ids of the units that were blocked, and why.
+  pub fn advance_units(&mut self, bounds: &MapBounds) -> Vec<(EntityId, String)> {
+    let mut blocked = Vec::new();
+    for (&id, unit) in &mut self.units {
+      if let Err(reason) = unit.advance(bounds) {
+        blocked.push((id, reason));
+      }
+    }
+
+    blocked
+  }
+
+  // Whether one of the player's units already has a name.
+  pub fn has_unit_named(&self, name: &str) -> bool {
+    self.units.values().any(|unit| unit.name() == name)
+  }
+
+  fn unit_mut(&mut self, id: EntityId) -> Result<&mut Box<Unit>, String> {
+    let name = &self.name;
+    self.units.get_mut(&id).ok_or_else(|| format!("player {} has no unit {}", name, id))
+  }
 }
 
 impl ToPyObject for _Player {
@@ -25,6 +96,8 @@ impl ToPyObject for _Player {
         let dict = PyDict::new(py);
         dict.set_item(py, "name", self.name.clone()).unwrap();
         dict.set_item(py, "units", self.units.clone()).unwrap();
+        dict.set_item(py, "team", self.team.clone()).unwrap();
+        dict.set_item(py, "faction", self.faction.clone()).unwrap();
         dict
     }
 }
@@ -1,24 +1,60 @@
 extern crate toml;
 
+use account::{_Account, _Accounts};
+use admin::{_AdminCommand, _AdminResult, HELP};
+use archetype::{UnitArchetype, _UnitRegistry};
+use ban::_Bans;
 use conf::*;
+use connection::{_Connection, _UdpRoutes};
+use entity::{_Entities, _OwnedUnit};
+use event::_Event;
+use limiter::{_Limiter, _MessageBudget};
+use metrics::{answer_scrape, _MeteredStream, _Metrics, _MetricsSnapshot};
+use names::{self, _NameGenerator};
 use player::*;
+use session::_Session;
+use simulation::{self, _TickCommand, _TickStats};
+use status::{query_status, _Status};
+use snapshot::_ClientSnapshots;
+use tls;
 use unit::*;
 
-use cpython::PyResult;
-use std::borrow::Borrow;
-use std::cmp::Eq;
-use std::collections::HashMap;
+use cpython::{exc, PyErr, PyResult, Python};
+use hpnet::datagram::{decode as decode_datagram, encode_parts};
+use hpnet::datagram::{Datagram, MAX_DATAGRAM_LEN};
+use hpnet::delta::{diff, unit_map, UnitMap};
+use hpnet::discovery::{decode as decode_discovery, encode as encode_discovery};
+use hpnet::discovery::{Announcement, Discovery, DISCOVERY_MAGIC, MAX_DISCOVERY_LEN};
+use hpnet::frame::{decode, read_frame_max, write_message, FrameReader};
+use hpnet::logging;
+use hpnet::message::{ChatChannel, ChatMsg, EntityId, ErrorKind, ErrorMsg, Hello, Message, ServerStatus, StateUpdate, Welcome};
+use hpnet::message::{capability_names, peek_version, version_mismatch};
+use hpnet::message::{CAPABILITIES, CAP_CHAT, CAP_DELTA_SNAPSHOTS, CAP_UDP_SNAPSHOTS, PROTOCOL_VERSION};
+use hpnet::stream::Stream;
+use hpnet::websocket::WsStream;
+use native_tls::TlsAcceptor;
+use log;
+use rand;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs::File;
-use std::hash::Hash;
 use std::io;
-use std::io::{Read, Write};
+use std::io::{BufRead, Read};
 use std::net::TcpListener;
 use std::net::TcpStream;
-use std::sync::{Arc, RwLock};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 py_module_initializer!(hpserver, inithpserver, PyInit_hpserver, |py, m| {
     try!(m.add(py, "__doc__", "This module is implemented in Rust."));
     try!(m.add_class::<Server>(py));
+    try!(m.add(py, "query_status", py_fn!(py, py_query_status(address: &str))));
+    try!(m.add(py, "query_status_tls", py_fn!(py, py_query_status_tls(address: &str, pinned_fingerprint: Option<String>))));
     
     Ok(())
 });
@@ -34,91 +70,449 @@ py_class!(class Server |py| {
 
     def get_name(&self) -> PyResult<String> {
         let server = self.server(py);
-        let name = server.data.read().unwrap().name.to_string();
+        let name = server.read_data().name.to_string();
 
         Ok(name)
     }
 
     def get_conf(&self) -> PyResult<ServerConf> {
         let server = self.server(py);
-        let conf = server.data.read().unwrap().conf.clone();
+        let conf = server.read_data().conf.clone();
 
         Ok(conf)
     }
 
+    // The same status that query_status() gets over the network.
+    def get_status(&self) -> PyResult<_Status> {
+        let server = self.server(py);
+
+        Ok(_Status(server.status()))
+    }
+
+    // How the game loop is keeping up with its tick rate, and the seed
+    // the game was started with.
+    def get_tick_stats(&self) -> PyResult<_TickStats> {
+        let server = self.server(py);
+
+        Ok(server.tick_stats())
+    }
+
+    // Counters and gauges for monitoring, the same ones served to
+    // Prometheus when [server.metrics] is turned on.
+    def get_metrics(&self) -> PyResult<_MetricsSnapshot> {
+        let server = self.server(py);
+
+        Ok(server.metrics())
+    }
+
     def get_players(&self) -> PyResult<HashMap<String, _Player>> {
         let server = self.server(py);
-        let players = server.data.read().unwrap().players.clone();
+        let players = server.read_data().players.clone();
         
         Ok(players)
     }
 
+    // How many players are waiting for a free slot.
+    def get_queue_length(&self) -> PyResult<usize> {
+        let server = self.server(py);
+        let len = server.read_data().queue.len();
+
+        Ok(len)
+    }
+
     def is_connected(&self, name: &str) -> PyResult<bool> {
         let server = self.server(py);
-        match server.data.read().unwrap().cons.get(name) {
+        match server.read_data().cons.get(name) {
             Some(res) => {
                 match res {
                     Some(_) => {
-                        println!("player {} is connected", name);
+                        debug!("player {} is connected", name);
                         Ok(true)
                     },
                     None => {
-                        println!("player {} is known but not connected", name);
+                        debug!("player {} is known but not connected", name);
                         Ok(false)
                     }
                 }
             },
             None => {
-                println!("player {} is not connected", name);
+                debug!("player {} is not connected", name);
                 Ok(false)
             }
         }
     }
 
+    // Listen on the address in the config until stop() is called. Raises
+    // IOError if it can't listen there, like restart().
     def listen(&self) -> PyResult<i32> {
+        let server = self.server(py);
+        match py.allow_threads(|| server.listen()) {
+            Ok(_) => Ok(0),
+            Err(e) => Err(PyErr::new::<exc::IOError, _>(py, e.to_string())),
+        }
+    }
+
+    def listen_to(&self, address: &str) -> PyResult<i32> {
+        let server = self.server(py);
+        match py.allow_threads(|| server.listen_to(address)) {
+            Ok(_) => Ok(0),
+            Err(e) => Err(PyErr::new::<exc::IOError, _>(py, e.to_string())),
+        }
+    }
+
+    // Take every event that has happened since the last call.
+    def poll_events(&self) -> PyResult<Vec<_Event>> {
+        let server = self.server(py);
+        let events = server.write_data().events.drain(..).collect();
+
+        Ok(events)
+    }
+
+    // Take every command that has been carried out since the last call,
+    // with the tick it ran on. With the seed from get_tick_stats(), that's
+    // what it takes to replay the game. Only the latest are kept, so poll
+    // often to record a whole game.
+    def poll_commands(&self) -> PyResult<Vec<_TickCommand>> {
+        let server = self.server(py);
+        let commands = server.write_data().played.drain(..).collect();
+
+        Ok(commands)
+    }
+
+    // Give a player an account with a password, or change the password of
+    // an existing account.
+    def create_account(&self, name: &str, password: &str) -> PyResult<bool> {
+        let server = self.server(py);
+        match py.allow_threads(|| server.create_account(name, password)) {
+            Ok(_) => Ok(true),
+            Err(e) => Err(PyErr::new::<exc::ValueError, _>(py, e.to_string())),
+        }
+    }
+
+    // Remove a player's account. Returns False if there was no such
+    // account.
+    def revoke_account(&self, name: &str) -> PyResult<bool> {
+        let server = self.server(py);
+        match server.revoke_account(name) {
+            Ok(revoked) => Ok(revoked),
+            Err(e) => Err(PyErr::new::<exc::IOError, _>(py, e.to_string())),
+        }
+    }
+
+    // Put a player on a team, or take them off their team with None.
+    // Returns False if there is no such player.
+    def set_team(&self, name: &str, team: Option<String>) -> PyResult<bool> {
+        let server = self.server(py);
+        let mut data = server.write_data();
+        match data.players.get_mut(name) {
+            Some(player) => {
+                player.team = team;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Pick the faction whose syllables a player's units are named with, or
+    // go back to the default ones with None. Returns False if there is no
+    // such player.
+    def set_faction(&self, name: &str, faction: Option<String>) -> PyResult<bool> {
+        let server = self.server(py);
+        let mut data = server.write_data();
+        match data.players.get_mut(name) {
+            Some(player) => {
+                player.faction = faction;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Every kind of unit, by name, as loaded from the data files in
+    // units_dir.
+    def get_unit_types(&self) -> PyResult<BTreeMap<String, UnitArchetype>> {
+        let server = self.server(py);
+
+        Ok(server.unit_types())
+    }
+
+    // Give a player a new unit of one of the kinds from get_unit_types().
+    // Pass None for the name to have one made up. Returns the id of the
+    // unit, or raises ValueError if it can't be placed there.
+    def spawn_unit(&self, player: &str, unit_type: &str, name: Option<String>, x: i64, y: i64) -> PyResult<u64> {
+        let server = self.server(py);
+        match server.spawn_unit(player, unit_type, name.as_ref().map(String::as_str), x, y) {
+            Ok(id) => Ok(id),
+            Err(e) => Err(PyErr::new::<exc::ValueError, _>(py, e)),
+        }
+    }
+
+    // The name the player's next unit of a type would get if it's spawned
+    // without one, from the syllables for their faction and the unit type.
+    // No unit of theirs has it yet. Asking doesn't use the name up.
+    def gen_unit_name(&self, player: &str, unit_type: &str) -> PyResult<String> {
+        let server = self.server(py);
+        match server.gen_unit_name(player, unit_type) {
+            Ok(name) => Ok(name),
+            Err(e) => Err(PyErr::new::<exc::ValueError, _>(py, e)),
+        }
+    }
+
+    // Look up a unit by id. Returns its id, owner, type, name, hit points
+    // and position, or None if there's no such unit.
+    def get_unit(&self, unit: u64) -> PyResult<Option<_OwnedUnit>> {
+        let server = self.server(py);
+
+        Ok(server.unit(unit))
+    }
+
+    // Move a unit x and y steps, stopping at the edges of the map. Returns
+    // where it ended up, or raises ValueError if it couldn't move at all.
+    def move_unit(&self, unit: u64, x: i64, y: i64) -> PyResult<(i64, i64)> {
+        let server = self.server(py);
+        match server.move_unit(unit, x, y) {
+            Ok(pos) => Ok(pos),
+            Err(e) => Err(PyErr::new::<exc::ValueError, _>(py, e)),
+        }
+    }
+
+    // Send a chat message from the server to every player.
+    def say(&self, text: &str) -> PyResult<bool> {
+        let server = self.server(py);
+        match server.server_chat(ChatChannel::Global, None, text) {
+            Ok(_) => Ok(true),
+            Err(e) => Err(PyErr::new::<exc::ValueError, _>(py, e)),
+        }
+    }
+
+    // Send a chat message from the server to everyone on a team.
+    def say_to_team(&self, team: &str, text: &str) -> PyResult<bool> {
+        let server = self.server(py);
+        match server.server_chat(ChatChannel::Team, Some(team), text) {
+            Ok(_) => Ok(true),
+            Err(e) => Err(PyErr::new::<exc::ValueError, _>(py, e)),
+        }
+    }
+
+    // Send a chat message from the server to one player.
+    def whisper(&self, name: &str, text: &str) -> PyResult<bool> {
+        let server = self.server(py);
+        match server.server_chat(ChatChannel::Whisper(name.to_string()), None, text) {
+            Ok(_) => Ok(true),
+            Err(e) => Err(PyErr::new::<exc::ValueError, _>(py, e)),
+        }
+    }
+
+    // Run an admin command, like the ones typed at the console, and return
+    // a dict saying whether it worked, a message, and any rows it listed.
+    def admin(&self, command: &str) -> PyResult<_AdminResult> {
+        let server = self.server(py);
+        let res = py.allow_threads(|| server.admin(command));
+
+        Ok(res)
+    }
+
+    // Read admin commands from stdin and print their results, until stdin
+    // is closed.
+    def console(&self) -> PyResult<i32> {
         let server = self.server(py);
         py.allow_threads(|| {
-            server.listen().unwrap();
+            server.console();
         });
 
         Ok(0)
     }
 
-    def listen_to(&self, address: &str) -> PyResult<i32> {
+    def is_listening(&self) -> PyResult<bool> {
+        Ok(self.server(py).is_listening())
+    }
+
+    // Stop listening and disconnect everyone. Whichever thread called
+    // listen() or listen_to() returns once the listener is closed.
+    def stop(&self) -> PyResult<i32> {
         let server = self.server(py);
         py.allow_threads(|| {
-            server.listen_to(address).unwrap();
+            server.stop("server is shutting down");
         });
 
         Ok(0)
     }
+
+    // Stop the server, then listen again in the background on the same
+    // address as before.
+    def restart(&self) -> PyResult<i32> {
+        let server = self.server(py);
+        match py.allow_threads(|| server.restart()) {
+            Ok(_) => Ok(0),
+            Err(e) => Err(PyErr::new::<exc::IOError, _>(py, e.to_string())),
+        }
+    }
 });
 
-type _Connections = HashMap<String, Option<TcpStream>>;
+// How long a client thread waits for data before checking its outbox.
+const POLL_INTERVAL_MS: u64 = 50;
+
+// How long a new client has to send its hello message.
+const LOGIN_TIMEOUT_SECS: u64 = 10;
+
+// How many events to keep for scripts that aren't polling for them.
+const MAX_EVENTS: usize = 1000;
+
+// How many carried out commands to keep for scripts that aren't polling
+// for them.
+const MAX_PLAYED_COMMANDS: usize = 10000;
+
+// Most ticks to run back to back when the tick loop falls behind. Any
+// more are skipped.
+const MAX_CATCH_UP_TICKS: u32 = 5;
+
+// How often to forget hosts the rate limiter hasn't heard from lately.
+const LIMITER_PRUNE_SECS: u64 = 60;
+
+type _Connections = HashMap<String, Option<_Connection>>;
+
+// The sockets the server listens on.
+struct _Sockets {
+    tcp: TcpListener,
+    udp: UdpSocket,
+    websocket: Option<TcpListener>,
+}
 
 pub struct _Data {
     name: String,
+    started: Instant,
+    conf_path: String,
     conf: ServerConf,
     players: HashMap<String, _Player>,
     cons: _Connections,
+    sessions: HashMap<String, _Session>,
+    accounts: _Accounts,
+    bans: _Bans,
+    events: VecDeque<_Event>,
+    // Names of players waiting for a free slot, first in line first.
+    queue: VecDeque<String>,
+    // The latest global chat messages, oldest first.
+    chat_history: VecDeque<ChatMsg>,
+    // Commands from players, waiting for the tick they're stamped with.
+    commands: VecDeque<_TickCommand>,
+    // Commands that have been carried out, for scripts recording the game.
+    played: VecDeque<_TickCommand>,
+    // Ticks run so far. Commands queued now run on the next one.
+    tick: u64,
+    seed: u64,
+    unit_types: _UnitRegistry,
+    entities: _Entities,
+    names: _NameGenerator,
+}
+
+impl _Data {
+    fn player_count(&self) -> usize {
+        self.cons.values().filter(|con| con.is_some()).count()
+    }
+
+    // Whether another player can join right now. Admins can also take the
+    // reserved slots.
+    fn has_room(&self, admin: bool) -> bool {
+        let server_conf = &self.conf.server;
+        let mut slots = server_conf.max_players as usize;
+        if !admin {
+            slots = slots.saturating_sub(server_conf.reserved_slots as usize);
+        }
+
+        self.player_count() < slots
+    }
+
+    // The map being played. For now that's always the first one in the
+    // config.
+    fn current_map(&self) -> String {
+        self.conf.server.maps.first().map(|map| map.name.clone()).unwrap_or_default()
+    }
+
+    // The size of the current map, which units have to stay inside.
+    fn map_bounds(&self) -> MapBounds {
+        let (width, height) = self.conf.server.maps.first().map(|map| (map.width, map.height)).unwrap_or((0, 0));
+        MapBounds {
+            width: i64::from(width),
+            height: i64::from(height),
+        }
+    }
+
+    // The name of the player that owns a unit.
+    fn unit_owner(&self, id: EntityId) -> Option<&str> {
+        self.players
+            .values()
+            .find(|player| player.units.contains_key(&id))
+            .map(|player| player.name.as_str())
+    }
+
+    // Admins have to be logged in to an account, or anyone could use
+    // their name to get a reserved slot.
+    fn is_admin(&self, name: &str) -> bool {
+        self.conf.server.admins.iter().any(|admin| admin == name) && self.accounts.has_account(name)
+    }
+
+    fn push_event(&mut self, event: _Event) {
+        if self.events.len() >= MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    fn push_played(&mut self, cmd: _TickCommand) {
+        if self.played.len() >= MAX_PLAYED_COMMANDS {
+            self.played.pop_front();
+        }
+        self.played.push_back(cmd);
+    }
 }
 
+#[derive(Clone)]
 pub struct _Server {
     data: Arc<RwLock<_Data>>,
+    running: Arc<AtomicBool>,
+    listening: Arc<AtomicBool>,
+    address: Arc<RwLock<Option<String>>>,
+    clients: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    next_con_id: Arc<AtomicUsize>,
+    tls: Option<TlsAcceptor>,
+    udp_port: Arc<RwLock<Option<u16>>>,
+    // Kept apart from _Data, so refusing a flood of connections or
+    // messages never waits on the game state lock.
+    limiter: Arc<Mutex<_Limiter>>,
+    udp_routes: Arc<Mutex<_UdpRoutes>>,
+    metrics: Arc<_Metrics>,
+    // Kept apart from _Data, like the limiter, so watching the tick loop
+    // doesn't slow it down.
+    tick_stats: Arc<Mutex<_TickStats>>,
 }
 
 impl _Server {
     pub fn new(conf_path: &str) -> Self {
         let name = String::from("Hostile Planets server");
-        println!("Loading {} ...", name);
 
-        let mut f = File::open(conf_path).expect("file not found");
-        let mut contents = String::new();
-        f.read_to_string(&mut contents)
-            .expect("something went wrong reading the file");
+        let conf = read_conf(conf_path).unwrap();
+        match logging::init(&conf.server.log) {
+            Ok(_) => {}
+            // Some other logger was set up first, and gets our records
+            // instead.
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                warn!("ignoring [server.log], logging is already set up: {}", e)
+            }
+            Err(e) => panic!("failed setting up logging: {}", e),
+        }
 
-        let conf: ServerConf = toml::from_str(&contents).unwrap();
-        println!("using config {}: {:?}", conf_path, conf);
+        info!("Loading {} ...", name);
+        debug!("using config {}: {:?}", conf_path, conf);
+
+        let accounts = _Accounts::load(&conf.server.accounts_file).unwrap();
+        let bans = _Bans::load(&conf.server.bans_file).unwrap();
+        let unit_types = _UnitRegistry::load(&conf.server.units_dir).unwrap();
+        let factions = names::load_factions(&conf.server.names_dir).unwrap();
+        let conf_dir = Path::new(conf_path).parent().unwrap_or_else(|| Path::new(""));
+        let tls = tls::acceptor(&conf.server.tls, &conf.server.ip, conf_dir).unwrap();
+        let limiter = _Limiter::new(conf.server.limits.clone());
+        let seed = conf.server.seed.unwrap_or_else(rand::random);
+        info!("game seed: {}", seed);
 
         let player_name = "default player";
         let mut p = HashMap::new();
@@ -130,76 +524,1760 @@ impl _Server {
         _Server {
             data: Arc::new(RwLock::new(_Data {
                 name: name,
+                started: Instant::now(),
+                conf_path: conf_path.to_string(),
                 conf: conf,
                 players: p,
                 cons: cons,    
-            }))
+                sessions: HashMap::new(),
+                accounts: accounts,
+                bans: bans,
+                events: VecDeque::new(),
+                queue: VecDeque::new(),
+                chat_history: VecDeque::new(),
+                commands: VecDeque::new(),
+                played: VecDeque::new(),
+                tick: 0,
+                seed: seed,
+                unit_types: unit_types,
+                entities: _Entities::new(),
+                names: _NameGenerator::new(factions, seed),
+            })),
+            running: Arc::new(AtomicBool::new(false)),
+            listening: Arc::new(AtomicBool::new(false)),
+            address: Arc::new(RwLock::new(None)),
+            clients: Arc::new(Mutex::new(Vec::new())),
+            next_con_id: Arc::new(AtomicUsize::new(0)),
+            tls: tls,
+            udp_port: Arc::new(RwLock::new(None)),
+            limiter: Arc::new(Mutex::new(limiter)),
+            udp_routes: Arc::new(Mutex::new(_UdpRoutes::new())),
+            metrics: Arc::new(_Metrics::new()),
+            tick_stats: Arc::new(Mutex::new(_TickStats::default())),
         }
     }
 
-    pub fn listen(&self) -> io::Result<()> {
-        let server_conf = self.data.read().unwrap().conf.clone().server;
+    // Address from the config file.
+    fn conf_address(&self) -> String {
+        let server_conf = self.read_data().conf.clone().server;
         let ip = server_conf.ip;
         let port = server_conf.port;
-        let address = format!("{}:{}", ip, port);
-        
-        self.listen_to(&address).unwrap();
 
-        Ok(())
+        format!("{}:{}", ip, port)
+    }
+
+    pub fn listen(&self) -> io::Result<()> {
+        let address = self.conf_address();
+
+        self.listen_to(&address)
     }
 
     pub fn listen_to(&self, address: &str) -> io::Result<()> {
-        let listener = TcpListener::bind(address).unwrap();
+        let sockets = self.bind(address)?;
+        self.serve(sockets)
+    }
 
-        {
-            let name = self.data.read().unwrap().name.clone();
-            println!("{} listening on: {}", name, address);
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+    }
+
+    // Lock the game state for reading, counting any time spent waiting for
+    // a writer to finish.
+    fn read_data(&self) -> RwLockReadGuard<_Data> {
+        match self.data.try_read() {
+            Ok(data) => data,
+            Err(TryLockError::WouldBlock) => {
+                let start = Instant::now();
+                let data = self.data.read().unwrap();
+                self.metrics.lock_waited(start.elapsed());
+                data
+            }
+            Err(TryLockError::Poisoned(e)) => panic!("game state lock poisoned: {}", e),
         }
+    }
 
-        for mut stream in listener.incoming() {
-            let key = String::from("Henry");
+    // Lock the game state for writing, counting any time spent waiting for
+    // other threads to let go of it.
+    fn write_data(&self) -> RwLockWriteGuard<_Data> {
+        match self.data.try_write() {
+            Ok(data) => data,
+            Err(TryLockError::WouldBlock) => {
+                let start = Instant::now();
+                let data = self.data.write().unwrap();
+                self.metrics.lock_waited(start.elapsed());
+                data
+            }
+            Err(TryLockError::Poisoned(e)) => panic!("game state lock poisoned: {}", e),
+        }
+    }
 
-            {
-                let mut players = self.data.write().unwrap().players.clone();
-                players.insert(
-                    key.clone(),
-                    _Player::new(&key, HashMap::new()),
-                );
+    pub fn metrics(&self) -> _MetricsSnapshot {
+        let mut metrics = self.metrics.snapshot();
+
+        let data = self.read_data();
+        metrics.players_connected = data.player_count();
+        metrics.players_queued = data.queue.len();
+        for player in data.players.values() {
+            for unit in player.units.values() {
+                *metrics.units.entry(unit.unit_type().name().to_string()).or_insert(0) += 1;
+            }
+        }
+
+        metrics
+    }
+
+    // Stop accepting connections, tell every connected client why they are
+    // being dropped, wait for their threads to finish writing, and save
+    // what needs saving.
+    pub fn stop(&self, reason: &str) {
+        // Queue the reason before anyone notices we're stopping, so it's
+        // what clients are told.
+        {
+            let data = self.read_data();
+            for con in data.cons.values() {
+                if let Some(ref con) = *con {
+                    con.send(Message::Disconnect {
+                        reason: String::from(reason),
+                    });
+                }
             }
+        }
+
+        self.running.store(false, Ordering::SeqCst);
+        while self.is_listening() {
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
 
-            {
-                self.data.write().unwrap().cons.insert(key.clone(), Some(stream.unwrap()));
+        let clients: Vec<JoinHandle<()>> = self.clients.lock().unwrap().drain(..).collect();
+        for client in clients {
+            if client.join().is_err() {
+                error!("a client thread panicked while shutting down");
             }
-            
-            self.handle_client(key);
         }
 
+        if let Err(e) = self.flush_state() {
+            error!("failed saving state while shutting down: {}", e);
+        }
+
+        let name = self.read_data().name.clone();
+        info!("{} stopped", name);
+        log::logger().flush();
+    }
+
+    // Write the accounts and bans out to their files.
+    fn flush_state(&self) -> io::Result<()> {
+        let data = self.read_data();
+        data.accounts.save(&data.conf.server.accounts_file)?;
+        data.bans.save(&data.conf.server.bans_file)
+    }
+
+    // Stop, then listen again in the background on the last address we
+    // listened on.
+    pub fn restart(&self) -> io::Result<()> {
+        let address = match self.address.read().unwrap().clone() {
+            Some(address) => address,
+            None => self.conf_address(),
+        };
+
+        self.stop("server is restarting");
+
+        let sockets = self.bind(&address)?;
+        let server = self.clone();
+        thread::spawn(move || {
+            if let Err(e) = server.serve(sockets) {
+                error!("listener failed: {}", e);
+            }
+        });
+
         Ok(())
     }
 
-    fn handle_client<'a, T>(&self, key: T)
-    where
-        T: Hash + Eq,
-        String: Borrow<T>,
-    {
-        let welcome_msg = "Welcome to Hostile Planets\n";
+    // Open the TCP listener, the UDP socket next to it, and the WebSocket
+    // listener if there is one.
+    fn bind(&self, address: &str) -> io::Result<_Sockets> {
+        let listener = TcpListener::bind(address)?;
 
-        {
-            let data = self.data.read().unwrap();
-            let players = data.players.clone();
-            let player = players.get(&key);
+        // Poll for new connections, so stop() can interrupt us.
+        listener.set_nonblocking(true)?;
+
+        let local = listener.local_addr()?;
+        let udp_port = match self.read_data().conf.server.udp_port {
+            Some(port) => port as u16,
+            None => local.port(),
+        };
+        let udp = UdpSocket::bind((local.ip(), udp_port))?;
 
-            let con = self.data.read().unwrap().cons.get(&key).unwrap().as_ref().unwrap().try_clone().unwrap();
-            println!("client connected: {:?} : {:?}", player, con);
-            println!("players: {:?}", players);
+        let websocket = match self.read_data().conf.server.websocket_port {
+            Some(port) => {
+                let websocket = TcpListener::bind((local.ip(), port as u16))?;
+                websocket.set_nonblocking(true)?;
+                Some(websocket)
+            }
+            None => None,
+        };
+
+        *self.address.write().unwrap() = Some(String::from(address));
+        *self.udp_port.write().unwrap() = Some(udp.local_addr()?.port());
+        // Together, so a stop() from here on waits for serve() to finish.
+        self.running.store(true, Ordering::SeqCst);
+        self.listening.store(true, Ordering::SeqCst);
+
+        {
+            let name = self.read_data().name.clone();
+            let encryption = if self.tls.is_some() { " (tls)" } else { "" };
+            info!("{} listening on: {}{}", name, address, encryption);
+            info!("{} sending snapshots from udp: {}", name, udp.local_addr()?);
+            if let Some(ref websocket) = websocket {
+                info!("{} accepting websockets on: {}", name, websocket.local_addr()?);
+            }
         }
 
+        Ok(_Sockets {
+            tcp: listener,
+            udp: udp,
+            websocket: websocket,
+        })
+    }
+
+    // Accept connections until stop() is called.
+    fn serve(&self, sockets: _Sockets) -> io::Result<()> {
+        let _Sockets { tcp: listener, udp, websocket } = sockets;
+
         {
-            let data = self.data.write().unwrap();
-            let mut con = data.cons.get(&key).unwrap().as_ref().unwrap().try_clone().unwrap();
-            con.write(welcome_msg.as_bytes()).unwrap();
+            // What each client has been sent, kept between ticks so
+            // snapshots can be sent as deltas, and updated as clients
+            // acknowledge them.
+            let snapshots = Arc::new(Mutex::new(HashMap::new()));
+            let tick_socket = udp.try_clone()?;
+
+            let server = self.clone();
+            let udp_snapshots = snapshots.clone();
+            let udp_thread = thread::spawn(move || server.serve_udp(udp, &udp_snapshots));
+            self.clients.lock().unwrap().push(udp_thread);
+
+            let server = self.clone();
+            let tick_thread = thread::spawn(move || server.serve_ticks(tick_socket, &snapshots));
+            self.clients.lock().unwrap().push(tick_thread);
+        }
+
+        // Another server on this machine may already be answering
+        // discovery probes, which shouldn't stop us serving players.
+        let discovery_conf = self.read_data().conf.server.discovery.clone();
+        if discovery_conf.enabled {
+            let tcp_addr = listener.local_addr()?;
+            match UdpSocket::bind(("0.0.0.0", discovery_conf.port as u16)) {
+                Ok(socket) => {
+                    let server = self.clone();
+                    let discovery_thread = thread::spawn(move || server.serve_discovery(socket, tcp_addr));
+                    self.clients.lock().unwrap().push(discovery_thread);
+                }
+                Err(e) => warn!("lan discovery is off, failed binding port {}: {}", discovery_conf.port, e),
+            }
+        }
+
+        // Like discovery, metrics aren't worth refusing to start over.
+        let metrics_conf = self.read_data().conf.server.metrics.clone();
+        if metrics_conf.enabled {
+            match TcpListener::bind(&metrics_conf.address) {
+                Ok(metrics_listener) => {
+                    info!("serving metrics on http://{}/metrics", metrics_conf.address);
+                    let server = self.clone();
+                    let metrics_thread = thread::spawn(move || server.serve_metrics(metrics_listener));
+                    self.clients.lock().unwrap().push(metrics_thread);
+                }
+                Err(e) => warn!("metrics are off, failed binding {}: {}", metrics_conf.address, e),
+            }
+        }
+
+        let mut last_prune = Instant::now();
+
+        while self.running.load(Ordering::SeqCst) {
+            if last_prune.elapsed() >= Duration::from_secs(LIMITER_PRUNE_SECS) {
+                self.limiter.lock().unwrap().prune();
+                last_prune = Instant::now();
+            }
+
+            let mut busy = self.accept(&listener, false);
+            if let Some(ref websocket) = websocket {
+                busy |= self.accept(websocket, true);
+            }
+
+            if !busy {
+                thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+            }
+        }
+
+        drop(listener);
+        drop(websocket);
+        self.listening.store(false, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    // Accept a connection if there is one waiting, and start serving it.
+    // Returns whether there was one, even if it was refused.
+    fn accept(&self, listener: &TcpListener, websocket: bool) -> bool {
+        let (stream, addr) = match listener.accept() {
+            Ok(res) => res,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+            Err(e) => {
+                warn!("failed accepting connection: {:?}", e);
+                return false;
+            }
+        };
+
+        // Refuse floods before they cost us a thread or a handshake.
+        if let Err(reason) = self.limiter.lock().unwrap().check_connect(addr.ip()) {
+            info!("refused connection from {}: {}", addr, reason);
+            return true;
+        }
+
+        if let Some(ban) = self.read_data().bans.ip_ban(addr.ip()) {
+            info!("refused connection from {}: banned: {}", addr, ban.reason);
+            return true;
+        }
+
+        if let Err(e) = stream.set_nonblocking(false) {
+            warn!("failed configuring connection: {:?}", e);
+            return true;
+        }
+
+        // Serve every client on its own thread, so a slow or idle player
+        // can't hold up anyone else.
+        let server = self.clone();
+        let client = thread::spawn(move || server.serve_client(stream, websocket));
+        let mut clients = self.clients.lock().unwrap();
+        // Forget the threads of clients that have left.
+        clients.retain(|client| !client.is_finished());
+        clients.push(client);
+
+        true
+    }
+
+    // Service the UDP socket until stop() is called. Clients register their
+    // UDP address with us, and acknowledge the snapshots they get.
+    fn serve_udp(&self, socket: UdpSocket, snapshots: &Mutex<HashMap<usize, _ClientSnapshots>>) {
+        if let Err(e) = socket.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS))) {
+            error!("failed configuring udp socket: {}", e);
+            return;
+        }
+
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        while self.running.load(Ordering::SeqCst) {
+            match socket.recv_from(&mut buf) {
+                Ok((len, addr)) => {
+                    self.metrics.received(len);
+                    // Floods are dropped here, before they cost us anything
+                    // else.
+                    if self.limiter.lock().unwrap().check_datagram(addr.ip()).is_ok() {
+                        self.handle_datagram(&buf[..len], addr, snapshots);
+                    }
+                }
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => warn!("failed receiving udp datagram: {}", e),
+            }
+        }
+    }
+
+    // Run the game until stop() is called. The game is advanced a fixed
+    // step at a time, and after every tick each client is sent the state
+    // of the world. This has a thread of its own, so waiting on the socket
+    // never holds up a tick.
+    fn serve_ticks(&self, socket: UdpSocket, snapshots: &Mutex<HashMap<usize, _ClientSnapshots>>) {
+        let (tick, tick_rate) = {
+            let data = self.read_data();
+            let tick_rate = data.conf.server.tick_rate;
+            *self.tick_stats.lock().unwrap() = _TickStats::new(data.seed, tick_rate);
+            (Duration::from_nanos(1_000_000_000 / u64::from(tick_rate)), tick_rate)
+        };
+        // read_conf keeps tick_rate at MAX_TICK_RATE or under, or this
+        // would be zero and the catch up below would never end.
+        assert!(tick > Duration::from_millis(0));
+        debug!("running {} ticks per second", tick_rate);
+
+        let mut seq = 0;
+        let mut next_tick = Instant::now() + tick;
+
+        while self.running.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if next_tick > now {
+                thread::sleep(next_tick - now);
+                continue;
+            }
+
+            // After a long stall, like the machine sleeping, skip ahead
+            // instead of running every missed tick at once.
+            let mut skipped = 0;
+            while next_tick + tick * MAX_CATCH_UP_TICKS < Instant::now() {
+                next_tick += tick;
+                skipped += 1;
+            }
+            if skipped > 0 {
+                warn!("tick loop fell behind, skipped {} ticks", skipped);
+                self.tick_stats.lock().unwrap().skipped += skipped;
+            }
+
+            let start = Instant::now();
+            seq += 1;
+            self.simulate();
+            self.send_snapshots(&socket, seq, &mut snapshots.lock().unwrap());
+            next_tick += tick;
+
+            let duration = start.elapsed();
+            self.metrics.ticked(duration);
+            self.tick_stats.lock().unwrap().record(duration, tick);
+        }
+    }
+
+    // Answer discovery probes from clients on the local network until
+    // stop() is called. A server that only listens on a loopback address
+    // can't be joined from other machines, so it only answers probes from
+    // this one.
+    fn serve_discovery(&self, socket: UdpSocket, tcp_addr: SocketAddr) {
+        if let Err(e) = socket.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS))) {
+            error!("failed configuring discovery socket: {}", e);
+            return;
+        }
+
+        let mut buf = vec![0; MAX_DISCOVERY_LEN];
+        while self.running.load(Ordering::SeqCst) {
+            let (len, addr) = match socket.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    warn!("failed receiving discovery probe: {}", e);
+                    continue;
+                }
+            };
+            self.metrics.received(len);
+
+            if tcp_addr.ip().is_loopback() && !addr.ip().is_loopback() {
+                continue;
+            }
+
+            let nonce = match decode_discovery(&buf[..len]) {
+                Ok(Discovery::Probe { magic, nonce }) if magic == DISCOVERY_MAGIC => nonce,
+                _ => continue,
+            };
+
+            let announcement = {
+                let data = self.read_data();
+                Announcement {
+                    nonce: nonce,
+                    name: data.name.clone(),
+                    map: data.current_map(),
+                    players: data.player_count() as u32,
+                    max_players: data.conf.server.max_players,
+                    port: tcp_addr.port(),
+                }
+            };
+
+            let res = encode_discovery(&Discovery::Announce(announcement))
+                .and_then(|packet| socket.send_to(&packet, addr));
+            if let Ok(len) = res {
+                self.metrics.sent(len);
+            }
+            if let Err(e) = res {
+                warn!("failed answering discovery probe from {}: {}", addr, e);
+            }
+        }
+    }
+
+    // Answer scrapers until stop() is called. Scrapes are quick and rare,
+    // so they're answered one at a time on this thread.
+    fn serve_metrics(&self, listener: TcpListener) {
+        if let Err(e) = listener.set_nonblocking(true) {
+            error!("failed configuring metrics listener: {}", e);
+            return;
+        }
+
+        while self.running.load(Ordering::SeqCst) {
+            let (mut stream, addr) = match listener.accept() {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+                    continue;
+                }
+                Err(e) => {
+                    warn!("failed accepting metrics connection: {:?}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = answer_scrape(&mut stream, || self.metrics().to_prometheus()) {
+                warn!("failed answering metrics request from {}: {}", addr, e);
+            }
         }
     }
+
+    fn handle_datagram(&self, buf: &[u8], addr: SocketAddr, snapshots: &Mutex<HashMap<usize, _ClientSnapshots>>) {
+        let datagram = match decode_datagram(buf) {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                warn!("bad udp datagram from {}: {}", addr, e);
+                self.limiter.lock().unwrap().strike(addr.ip(), &e.to_string());
+                return;
+            }
+        };
+
+        match datagram {
+            Datagram::Register { token } => {
+                if !self.udp_routes.lock().unwrap().register(token, addr) {
+                    debug!("udp registration from {} with an unknown token", addr);
+                }
+            }
+
+            Datagram::Ack { token, seq } => {
+                if let Some(con_id) = self.udp_con_id(token, addr) {
+                    if let Some(client) = snapshots.lock().unwrap().get_mut(&con_id) {
+                        client.ack(seq);
+                    }
+                }
+            }
+
+            Datagram::Resync { token } => {
+                if let Some(con_id) = self.udp_con_id(token, addr) {
+                    if let Some(client) = snapshots.lock().unwrap().get_mut(&con_id) {
+                        client.resync();
+                    }
+                }
+            }
+
+            datagram => warn!("unexpected udp datagram from {}: {:?}", addr, datagram),
+        }
+    }
+
+    // The id of the connection that registered this token and address.
+    fn udp_con_id(&self, token: u64, addr: SocketAddr) -> Option<usize> {
+        self.udp_routes.lock().unwrap().con_id(token, addr)
+    }
+
+    // Advance the game by one tick.
+    fn simulate(&self) {
+        let mut data = self.write_data();
+        let bounds = data.map_bounds();
+        data.tick += 1;
+        let tick = data.tick;
+        let commands: Vec<_TickCommand> = data.commands.drain(..).collect();
+
+        for event in simulation::step(&mut data.players, tick, &commands, &bounds) {
+            data.push_event(event);
+        }
+        for cmd in commands {
+            data.push_played(cmd);
+        }
+    }
+
+    pub fn tick_stats(&self) -> _TickStats {
+        self.tick_stats.lock().unwrap().clone()
+    }
+
+    // Send the state of every unit to every client with a UDP address, as
+    // the changes since the last snapshot each client acknowledged, and
+    // hand it to the connections of the rest.
+    fn send_snapshots(&self, socket: &UdpSocket, seq: u64, snapshots: &mut HashMap<usize, _ClientSnapshots>) {
+        // Never hold the routes while waiting for the game state lock.
+        let targets = self.udp_routes.lock().unwrap().targets();
+
+        let (units, tcp_targets) = {
+            let data = self.read_data();
+
+            let mut units = Vec::new();
+            for player in data.players.values() {
+                units.extend(player.unit_states());
+            }
+
+            // Clients that can't use UDP, like browsers, get the newest
+            // state over their connection instead. So do clients whose UDP
+            // registration hasn't got through yet, or ever will, from
+            // behind a firewall.
+            let tcp_targets: Vec<_Connection> = data
+                .cons
+                .values()
+                .filter_map(|con| con.as_ref())
+                .filter(|con| {
+                    con.capabilities & CAP_UDP_SNAPSHOTS == 0 || !targets.iter().any(|&(id, _, _)| id == con.id)
+                })
+                .cloned()
+                .collect();
+
+            (unit_map(units), tcp_targets)
+        };
+
+        if !tcp_targets.is_empty() {
+            let shared = Arc::new(units.clone());
+            for con in &tcp_targets {
+                con.set_state(shared.clone());
+            }
+        }
+
+        // Forget about clients that have gone away.
+        snapshots.retain(|con_id, _| targets.iter().any(|&(id, _, _)| id == *con_id));
+
+        for (con_id, addr, capabilities) in targets {
+            let datagram = snapshots
+                .entry(con_id)
+                .or_insert_with(|| _ClientSnapshots::new(capabilities))
+                .next(seq, &units);
+
+            let parts = match encode_parts(&datagram) {
+                Ok(parts) => parts,
+                Err(e) => {
+                    error!("failed encoding snapshot: {}", e);
+                    continue;
+                }
+            };
+
+            for buf in parts {
+                match socket.send_to(&buf, addr) {
+                    Ok(len) => self.metrics.sent(len),
+                    Err(e) => warn!("failed sending snapshot to {}: {}", addr, e),
+                }
+            }
+        }
+    }
+
+    // Log the client in, then serve it until it disconnects.
+    fn serve_client(&self, stream: TcpStream, websocket: bool) {
+        let mut stream = match self.wrap_stream(stream, websocket) {
+            Ok(stream) => _MeteredStream::new(stream, self.metrics.clone()),
+            Err(e) => {
+                warn!("failed setting up connection: {}", e);
+                return;
+            }
+        };
+
+        let greeting = match self.read_greeting(&mut stream) {
+            Ok(msg) => msg,
+            Err(e) => {
+                info!("login failed: {}", e);
+                return;
+            }
+        };
+
+        let hello = match greeting {
+            Message::Hello(hello) => hello,
+
+            // Monitoring tools ask for our status without logging in.
+            Message::QueryStatus => {
+                if let Err(e) = write_message(&mut stream, &Message::Status(self.status())) {
+                    warn!("failed answering status query: {}", e);
+                }
+                return;
+            }
+
+            msg => {
+                let e = refuse(
+                    &mut stream,
+                    ErrorKind::Protocol,
+                    &format!("expected a hello message but got: {:?}", msg),
+                );
+                info!("login failed: {}", e);
+                if let Ok(addr) = stream.peer_addr() {
+                    self.limiter.lock().unwrap().strike(addr.ip(), &e.to_string());
+                }
+                return;
+            }
+        };
+
+        let (outbox, inbox) = channel();
+
+        let (key, con_id, session) = match self.login(&mut stream, hello, outbox) {
+            Ok(res) => res,
+            Err(e) => {
+                info!("login failed: {}", e);
+                return;
+            }
+        };
+
+        let reason = match self.handle_client(&key, &session, stream, inbox) {
+            Ok(reason) => reason,
+            Err(e) => {
+                warn!("connection to player {} failed: {}", key, e);
+                e.to_string()
+            }
+        };
+
+        self.disconnect(&key, con_id, &reason);
+    }
+
+    // Do the TLS handshake with a new client if TLS is turned on, then the
+    // WebSocket handshake if it came in on the WebSocket port.
+    fn wrap_stream(&self, stream: TcpStream, websocket: bool) -> io::Result<Stream> {
+        // Don't let a client that never finishes a handshake hold on to a
+        // thread.
+        stream.set_read_timeout(Some(Duration::from_secs(LOGIN_TIMEOUT_SECS)))?;
+
+        let stream = match self.tls {
+            Some(ref acceptor) => match acceptor.accept(stream) {
+                Ok(stream) => Stream::Tls(stream),
+                Err(e) => {
+                    return Err(io::Error::other(format!("tls handshake failed: {}", e)))
+                }
+            },
+            None => Stream::Plain(stream),
+        };
+
+        if websocket {
+            Ok(Stream::WebSocket(Box::new(WsStream::accept(stream)?)))
+        } else {
+            Ok(stream)
+        }
+    }
+
+    // Read the first message from a new client, which is either a hello or
+    // a status query.
+    fn read_greeting(&self, stream: &mut _MeteredStream) -> io::Result<Message> {
+        let addr = stream.peer_addr()?;
+
+        // Don't let a client that never says hello hold on to a thread.
+        stream.set_read_timeout(Some(Duration::from_secs(LOGIN_TIMEOUT_SECS)))?;
+
+        let max_len = self.read_data().conf.server.limits.max_message_bytes;
+        let body = match read_frame_max(stream, max_len) {
+            Ok(body) => body,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::InvalidData {
+                    self.limiter.lock().unwrap().strike(addr.ip(), &e.to_string());
+                }
+                return Err(e);
+            }
+        };
+
+        // Turn away clients that speak another version of the protocol
+        // with an error they can read, even if we can't read their hello.
+        // Every version knows the protocol error kind.
+        let version = match decode(&body) {
+            Ok(Message::Hello(ref hello)) => hello.protocol_version,
+            Ok(msg) => return Ok(msg),
+            Err(e) => match peek_version(&body) {
+                Some(version) => version,
+                None => {
+                    self.limiter.lock().unwrap().strike(addr.ip(), &e.to_string());
+                    return Err(e);
+                }
+            },
+        };
+
+        if version != PROTOCOL_VERSION {
+            info!("refused client from {}: {}", addr, version_mismatch(version));
+            return Err(refuse(stream, ErrorKind::Protocol, &version_mismatch(version)));
+        }
+
+        decode(&body)
+    }
+
+    pub fn status(&self) -> ServerStatus {
+        let data = self.read_data();
+
+        let mut players: Vec<String> = data.cons
+            .iter()
+            .filter(|&(_, con)| con.is_some())
+            .map(|(name, _)| name.clone())
+            .collect();
+        players.sort();
+
+        ServerStatus {
+            name: data.name.clone(),
+            protocol_version: PROTOCOL_VERSION,
+            map: data.current_map(),
+            players: players,
+            max_players: data.conf.server.max_players,
+            uptime_secs: data.started.elapsed().as_secs(),
+            tick_rate: data.conf.server.tick_rate,
+        }
+    }
+
+    // Register a client under the player name it sent in its hello.
+    // Returns the name the connection is stored under, and the
+    // connection's id.
+    fn login(&self, stream: &mut _MeteredStream, hello: Hello, outbox: Sender<Message>) -> io::Result<(String, usize, String)> {
+        let addr = stream.peer_addr()?;
+
+        let name = hello.name.trim().to_string();
+        if name.is_empty() {
+            return Err(refuse(
+                stream,
+                ErrorKind::InvalidName,
+                "player name can't be empty",
+            ));
+        }
+
+        // Server announcements go out under the server's name, so nobody
+        // can chat as the server.
+        let server_name = self.read_data().name.clone();
+        if name.to_lowercase() == server_name.to_lowercase() {
+            return Err(refuse(
+                stream,
+                ErrorKind::InvalidName,
+                &format!("player name {} is reserved for the server", name),
+            ));
+        }
+
+        let ban = self.read_data().bans.name_ban(&name).cloned();
+        if let Some(ban) = ban {
+            info!("refused banned player {} from {}: {}", name, addr, ban.reason);
+            return Err(refuse(stream, ErrorKind::Banned, &format!("banned: {}", ban.reason)));
+        }
+
+        if let Err(reason) = self.authenticate(&name, hello.password.as_ref().map(String::as_str)) {
+            warn!("authentication failed for player {} from {}: {}", name, addr, reason);
+            // So passwords can't be guessed any faster than the ban allows.
+            self.limiter.lock().unwrap().strike(addr.ip(), &reason);
+            return Err(refuse(stream, ErrorKind::Auth, &reason));
+        }
+
+        let mut data = self.write_data();
+        let session_timeout = Duration::from_secs(data.conf.server.session_timeout_secs);
+
+        // A client with the token for the player's session is that player
+        // coming back, maybe before we've noticed their old connection die.
+        let (resuming, reserved) = match data.sessions.get(&name) {
+            Some(session) if !session.is_expired(session_timeout) => {
+                (hello.session.as_ref() == Some(&session.token), true)
+            }
+            _ => (false, false),
+        };
+
+        if data.queue.contains(&name) {
+            drop(data);
+            return Err(refuse(
+                stream,
+                ErrorKind::NameTaken,
+                &format!("player name {} is waiting in the join queue", name),
+            ));
+        }
+
+        if reserved && !resuming {
+            let connected = match data.cons.get(&name) {
+                Some(&Some(_)) => true,
+                _ => false,
+            };
+            drop(data);
+
+            let reason = if connected {
+                format!("player name {} is already in use", name)
+            } else {
+                format!("player name {} is reserved for a player who is reconnecting", name)
+            };
+            return Err(refuse(stream, ErrorKind::NameTaken, &reason));
+        }
+
+        // Returning players get their slot back straight away. Everyone else
+        // waits their turn if the server is full, except admins, who can
+        // use the reserved slots.
+        if !resuming {
+            let admin = data.is_admin(&name);
+            let admitted = if admin {
+                data.has_room(true)
+            } else {
+                data.queue.is_empty() && data.has_room(false)
+            };
+
+            if !admitted {
+                if data.queue.len() >= data.conf.server.max_queue as usize {
+                    drop(data);
+                    info!("turned away player {} from {}: server is full", name, addr);
+                    return Err(refuse(
+                        stream,
+                        ErrorKind::ServerFull,
+                        "the server and its join queue are full",
+                    ));
+                }
+
+                // Admins go ahead of everyone but the admins already
+                // waiting.
+                let position = if admin {
+                    data.queue.iter().take_while(|queued| data.is_admin(queued)).count()
+                } else {
+                    data.queue.len()
+                };
+                data.queue.insert(position, name.clone());
+                info!("player {} is waiting in the join queue at position {}", name, position + 1);
+                drop(data);
+
+                data = self.wait_in_queue(stream, &name)?;
+            }
+        }
+
+        // The token goes out in the welcome. It's taken now, while the data
+        // is locked, since a ban could end the session before then.
+        let session = if resuming {
+            if let Some(&Some(ref old)) = data.cons.get(&name) {
+                old.send(Message::Disconnect {
+                    reason: String::from("logged in from another connection"),
+                });
+            }
+
+            info!("player {} resumed their session", name);
+            // Resuming was decided under this same lock, so the session is
+            // still there.
+            let session = data.sessions.get_mut(&name).unwrap();
+            session.disconnected_at = None;
+            session.token.clone()
+        } else {
+            let session = _Session::new();
+            let token = session.token.clone();
+            data.sessions.insert(name.clone(), session);
+            token
+        };
+
+        if !data.players.contains_key(&name) {
+            data.players
+                .insert(name.clone(), _Player::new(&name, Units::new()));
+        }
+
+        // Use the optional features we both support. Snapshots need the UDP
+        // socket, which may have failed to open, but deltas also work over
+        // the connection.
+        let mut capabilities = hello.capabilities & CAPABILITIES;
+        if self.udp_port.read().unwrap().is_none() {
+            capabilities &= !CAP_UDP_SNAPSHOTS;
+        }
+        debug!("player {} uses features: {:?}", name, capability_names(capabilities));
+
+        let con_id = self.next_con_id.fetch_add(1, Ordering::SeqCst);
+        let con = _Connection::new(con_id, addr, outbox, capabilities);
+        self.udp_routes.lock().unwrap().add(&con);
+        data.cons.insert(name.clone(), Some(con));
+        data.push_event(_Event::Connected {
+            player: name.clone(),
+        });
+
+        Ok((name, con_id, session))
+    }
+
+    // Hold a client in the join queue until a slot frees up for it, and
+    // take it out of the queue if it gives up. Returns with the data locked,
+    // so nobody else can take the slot before the caller registers it.
+    fn wait_in_queue(&self, stream: &mut _MeteredStream, name: &str) -> io::Result<RwLockWriteGuard<_Data>> {
+        let res = self.wait_for_turn(stream, name);
+        if res.is_err() {
+            self.write_data().queue.retain(|queued| queued != name);
+        }
+
+        res
+    }
+
+    fn wait_for_turn(&self, stream: &mut _MeteredStream, name: &str) -> io::Result<RwLockWriteGuard<_Data>> {
+        let (interval, limits) = {
+            let server_conf = &self.read_data().conf.server;
+            (
+                Duration::from_millis(server_conf.heartbeat_interval_ms),
+                server_conf.limits.clone(),
+            )
+        };
+
+        let ip = stream.peer_addr()?.ip();
+        stream.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS)))?;
+        let mut reader = FrameReader::with_max_len(limits.max_message_bytes);
+        let mut budget = _MessageBudget::new(limits.messages_per_second, limits.message_burst);
+        let mut told: Option<(usize, Instant)> = None;
+
+        loop {
+            if !self.running.load(Ordering::SeqCst) {
+                let reason = String::from("server is shutting down");
+                write_message(stream, &Message::Disconnect { reason: reason.clone() })?;
+                return Err(io::Error::other(reason));
+            }
+
+            let (position, length) = {
+                let mut data = self.write_data();
+
+                // Tell players banned while they wait why, like the
+                // players ban() disconnects.
+                let ban = data.bans.name_ban(name).or_else(|| data.bans.ip_ban(ip)).cloned();
+                if let Some(ban) = ban {
+                    drop(data);
+                    let reason = format!("banned: {}", ban.reason);
+                    write_message(stream, &Message::Disconnect { reason: reason.clone() })?;
+                    return Err(io::Error::other(reason));
+                }
+
+                let position = match data.queue.iter().position(|queued| queued == name) {
+                    Some(position) => position,
+                    None => {
+                        return Err(io::Error::other("dropped from the join queue"))
+                    }
+                };
+
+                // Admins can take the reserved slots here too.
+                if position == 0 && data.has_room(data.is_admin(name)) {
+                    data.queue.pop_front();
+                    info!("player {} left the join queue for a free slot", name);
+                    return Ok(data);
+                }
+
+                (position + 1, data.queue.len())
+            };
+
+            // Tell the client when its place changes, and every so often
+            // anyway so it knows we're still here.
+            let stale = match told {
+                Some((told_position, at)) => told_position != position || at.elapsed() >= interval,
+                None => true,
+            };
+            if stale {
+                write_message(stream, &Message::Queued {
+                    position: position as u32,
+                    length: length as u32,
+                })?;
+                told = Some((position, Instant::now()));
+            }
+
+            // A client that hangs up shows up as an error here.
+            if let Some(msg) = reader.poll(stream)? {
+                if !budget.take() {
+                    let reason = String::from("sending messages too fast");
+                    self.limiter.lock().unwrap().strike(ip, &reason);
+                    write_message(stream, &Message::Disconnect { reason: reason.clone() })?;
+                    return Err(io::Error::other(reason));
+                }
+
+                match msg {
+                    Message::Ping(seq) => write_message(stream, &Message::Pong(seq))?,
+                    Message::Disconnect { reason } => {
+                        return Err(io::Error::other(format!("left the join queue: {}", reason)))
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Check a player's password against their account, if they have one.
+    fn authenticate(&self, name: &str, password: Option<&str>) -> Result<(), String> {
+        let (account, require_accounts) = {
+            let data = self.read_data();
+            (
+                data.accounts.accounts.get(name).cloned(),
+                data.conf.server.require_accounts,
+            )
+        };
+
+        // Hashing is slow on purpose, so do it without holding the lock.
+        match (account, password) {
+            (Some(account), Some(password)) => {
+                if account.verify(password) {
+                    Ok(())
+                } else {
+                    Err(String::from("wrong password"))
+                }
+            }
+            (Some(_), None) => Err(format!("player {} needs a password", name)),
+            (None, _) if require_accounts => Err(format!("player {} has no account", name)),
+            (None, _) => Ok(()),
+        }
+    }
+
+    // Add an account, or change the password of an existing one, and save
+    // the accounts file.
+    pub fn create_account(&self, name: &str, password: &str) -> io::Result<()> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "player name can't be empty",
+            ));
+        }
+
+        let account = _Account::new(password)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let mut data = self.write_data();
+        data.accounts.insert(name, account);
+        data.accounts.save(&data.conf.server.accounts_file)?;
+
+        info!("created account for player {}", name);
+
+        Ok(())
+    }
+
+    // Remove an account and save the accounts file. The player's session
+    // is dropped too, and they are disconnected if they are logged in.
+    // Returns false if there was no such account.
+    pub fn revoke_account(&self, name: &str) -> io::Result<bool> {
+        let mut data = self.write_data();
+        if !data.accounts.revoke(name) {
+            return Ok(false);
+        }
+        data.accounts.save(&data.conf.server.accounts_file)?;
+
+        data.sessions.remove(name);
+        if let Some(&Some(ref con)) = data.cons.get(name) {
+            con.send(Message::Disconnect {
+                reason: String::from("account revoked"),
+            });
+        }
+
+        info!("revoked account for player {}", name);
+
+        Ok(true)
+    }
+
+    // Talk to a logged in client until either side hangs up, and return
+    // why. Messages queued in the client's outbox are written out between
+    // reads, and the client is pinged every heartbeat interval.
+    fn handle_client(&self, key: &str, session: &str, mut stream: _MeteredStream, inbox: Receiver<Message>) -> io::Result<String> {
+        let addr = stream.peer_addr()?;
+
+        let (con, welcome, interval, timeout, limits, history) = {
+            let data = self.read_data();
+            info!("player {} connected from {}", key, addr);
+            debug!("connection to player {}: {:?}", key, stream);
+
+            let con = match data.cons.get(key) {
+                Some(&Some(ref con)) => con.clone(),
+                _ => return Ok(String::from("connection closed by server")),
+            };
+            let (udp_token, capabilities) = (con.udp_token, con.capabilities);
+
+            let udp_port = if capabilities & CAP_UDP_SNAPSHOTS != 0 {
+                *self.udp_port.read().unwrap()
+            } else {
+                None
+            };
+
+            let history = if capabilities & CAP_CHAT != 0 {
+                data.chat_history.clone()
+            } else {
+                VecDeque::new()
+            };
+
+            let server_conf = &data.conf.server;
+            let welcome = Message::Welcome(Welcome {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: capabilities,
+                server_name: data.name.clone(),
+                message: String::from("Welcome to Hostile Planets"),
+                heartbeat_timeout_ms: server_conf.heartbeat_timeout_ms,
+                session: String::from(session),
+                udp_port: udp_port,
+                udp_token: udp_token,
+            });
+
+            (
+                con,
+                welcome,
+                Duration::from_millis(server_conf.heartbeat_interval_ms),
+                Duration::from_millis(server_conf.heartbeat_timeout_ms),
+                server_conf.limits.clone(),
+                history,
+            )
+        };
+
+        write_message(&mut stream, &welcome)?;
+
+        // Catch the player up on what's been said.
+        for msg in history {
+            write_message(&mut stream, &Message::Chat(msg))?;
+        }
+
+        stream.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS)))?;
+        let mut reader = FrameReader::with_max_len(limits.max_message_bytes);
+        let mut budget = _MessageBudget::new(limits.messages_per_second, limits.message_burst);
+        let mut last_seen = Instant::now();
+        let mut last_ping = Instant::now();
+        let mut ping_seq = 0;
+
+        // The state the client was last sent, that deltas are made against.
+        let deltas = con.capabilities & CAP_DELTA_SNAPSHOTS != 0;
+        let mut sent: Option<Arc<UnitMap>> = None;
+
+        loop {
+            // Before checking whether we're stopping, so the reason stop()
+            // queued gets sent.
+            loop {
+                match inbox.try_recv() {
+                    Ok(msg) => {
+                        write_message(&mut stream, &msg)?;
+                        if let Message::Disconnect { reason } = msg {
+                            return Ok(reason);
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        return Ok(String::from("connection closed by server"))
+                    }
+                }
+            }
+
+            if !self.running.load(Ordering::SeqCst) {
+                let reason = String::from("server is shutting down");
+                write_message(&mut stream, &Message::Disconnect { reason: reason.clone() })?;
+                return Ok(reason);
+            }
+
+            if last_seen.elapsed() > timeout {
+                let reason = String::from("timed out");
+                write_message(&mut stream, &Message::Disconnect { reason: reason.clone() })?;
+                return Ok(reason);
+            }
+
+            if let Some(units) = con.take_state() {
+                let update = match sent {
+                    Some(ref base) if deltas => {
+                        let (changed, removed) = diff(base, &units);
+                        if changed.is_empty() && removed.is_empty() {
+                            None
+                        } else {
+                            Some(StateUpdate::Delta { changed: changed, removed: removed })
+                        }
+                    }
+                    _ => Some(StateUpdate::Units(units.values().cloned().collect())),
+                };
+                if let Some(update) = update {
+                    write_message(&mut stream, &Message::StateUpdate(update))?;
+                }
+                sent = Some(units);
+            }
+
+            // Even when the client is busy talking to us, since it may hear
+            // nothing else over TCP while its snapshots go over UDP.
+            if last_ping.elapsed() >= interval {
+                ping_seq += 1;
+                write_message(&mut stream, &Message::Ping(ping_seq))?;
+                last_ping = Instant::now();
+            }
+
+            let msg = match reader.poll(&mut stream) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::InvalidData {
+                        self.limiter.lock().unwrap().strike(addr.ip(), &e.to_string());
+                    }
+                    return Err(e);
+                }
+            };
+
+            if let Some(msg) = msg {
+                last_seen = Instant::now();
+
+                if !budget.take() {
+                    let reason = String::from("sending messages too fast");
+                    info!("kicking player {} from {}: {}", key, addr, reason);
+                    self.limiter.lock().unwrap().strike(addr.ip(), &reason);
+                    write_message(&mut stream, &Message::Disconnect { reason: reason.clone() })?;
+                    return Ok(reason);
+                }
+
+                if let Some(reason) = self.handle_message(key, &mut stream, msg)? {
+                    return Ok(reason);
+                }
+            }
+        }
+    }
+
+    // Act on one message from a logged in client. Returns the reason for
+    // closing the connection, once it should be closed.
+    fn handle_message(&self, key: &str, stream: &mut _MeteredStream, msg: Message) -> io::Result<Option<String>> {
+        match msg {
+            // Commands are carried out on the next tick, in the order they
+            // arrived, and stamped with it so the game can be replayed.
+            Message::Command(cmd) => {
+                let mut data = self.write_data();
+                let tick = data.tick + 1;
+                debug!("player {} sent command for tick {}: {:?}", key, tick, cmd);
+                data.commands.push_back(_TickCommand {
+                    tick: tick,
+                    player: key.to_string(),
+                    command: cmd,
+                });
+                Ok(None)
+            }
+
+            Message::Say { channel, text } => {
+                if let Err(reason) = self.chat(key, channel, &text) {
+                    write_message(stream, &Message::Error(ErrorMsg::new(ErrorKind::Chat, &reason)))?;
+                }
+                Ok(None)
+            }
+
+            Message::Ping(seq) => {
+                write_message(stream, &Message::Pong(seq))?;
+                Ok(None)
+            }
+
+            Message::Pong(_) => Ok(None),
+
+            Message::Disconnect { reason } => {
+                info!("player {} disconnected: {}", key, reason);
+                Ok(Some(reason))
+            }
+
+            msg => {
+                let e = ErrorMsg::new(
+                    ErrorKind::Protocol,
+                    &format!("unexpected message: {:?}", msg),
+                );
+                write_message(stream, &Message::Error(e))?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Run one admin command, and report what it did.
+    pub fn admin(&self, line: &str) -> _AdminResult {
+        let cmd = match _AdminCommand::parse(line) {
+            Ok(cmd) => cmd,
+            Err(e) => return _AdminResult::err(&e),
+        };
+        info!("admin command: {:?}", cmd);
+
+        match cmd {
+            _AdminCommand::List => self.list_players(),
+            _AdminCommand::Kick { name, reason } => self.kick(&name, &reason),
+            _AdminCommand::Ban { target, reason } => self.ban(&target, &reason),
+            _AdminCommand::Unban { target } => self.unban(&target),
+            _AdminCommand::Bans => self.list_bans(),
+            _AdminCommand::Say { text } => match self.server_chat(ChatChannel::Global, None, &text) {
+                Ok(_) => _AdminResult::ok(&format!("said: {}", text)),
+                Err(e) => _AdminResult::err(&e),
+            },
+            _AdminCommand::Reload => match self.reload_conf() {
+                Ok(_) => _AdminResult::ok(
+                    "reloaded the config, accounts and bans; address, udp port and tls changes take effect after a restart",
+                ),
+                Err(e) => _AdminResult::err(&format!("failed reloading: {}", e)),
+            },
+            _AdminCommand::Help => _AdminResult::ok(HELP),
+        }
+    }
+
+    // Read admin commands from stdin until it is closed, printing the
+    // result of each.
+    pub fn console(&self) {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("failed reading the console: {}", e);
+                    return;
+                }
+            };
+
+            if !line.trim().is_empty() {
+                println!("{}", self.admin(&line));
+            }
+        }
+    }
+
+    fn list_players(&self) -> _AdminResult {
+        let data = self.read_data();
+
+        let mut names: Vec<&String> = data.cons.keys().collect();
+        names.sort();
+
+        let mut rows = Vec::new();
+        for name in names {
+            let mut row = BTreeMap::new();
+            row.insert(String::from("name"), name.clone());
+            match data.cons[name] {
+                Some(ref con) => {
+                    row.insert(String::from("status"), String::from("connected"));
+                    row.insert(String::from("address"), con.addr.to_string());
+                    row.insert(String::from("connection"), con.id.to_string());
+                }
+                None => {
+                    row.insert(String::from("status"), String::from("disconnected"));
+                }
+            }
+            if let Some(team) = data.players.get(name).and_then(|player| player.team.clone()) {
+                row.insert(String::from("team"), team);
+            }
+            rows.push(row);
+        }
+
+        for (i, name) in data.queue.iter().enumerate() {
+            let mut row = BTreeMap::new();
+            row.insert(String::from("name"), name.clone());
+            row.insert(String::from("status"), String::from("queued"));
+            row.insert(String::from("position"), (i + 1).to_string());
+            rows.push(row);
+        }
+
+        let mut res = _AdminResult::ok(&format!(
+            "{} of {} players connected, {} queued",
+            data.player_count(),
+            data.conf.server.max_players,
+            data.queue.len()
+        ));
+        res.rows = rows;
+
+        res
+    }
+
+    // Disconnect a player. They can log straight back in.
+    pub fn kick(&self, name: &str, reason: &str) -> _AdminResult {
+        let data = self.read_data();
+        match data.cons.get(name) {
+            Some(&Some(ref con)) => {
+                con.send(Message::Disconnect {
+                    reason: format!("kicked: {}", reason),
+                });
+                _AdminResult::ok(&format!("kicked player {}: {}", name, reason))
+            }
+            _ => _AdminResult::err(&format!("player {} is not connected", name)),
+        }
+    }
+
+    // Ban a player name, or an address if the target is one, disconnect
+    // whoever it matches, and save the ban file.
+    pub fn ban(&self, target: &str, reason: &str) -> _AdminResult {
+        let mut data = self.write_data();
+        let kick = Message::Disconnect {
+            reason: format!("banned: {}", reason),
+        };
+
+        match target.parse::<IpAddr>() {
+            Ok(ip) => {
+                data.bans.ban_ip(ip, reason);
+                for con in data.cons.values() {
+                    if let Some(ref con) = *con {
+                        if con.addr.ip() == ip {
+                            con.send(kick.clone());
+                        }
+                    }
+                }
+            }
+            Err(_) => {
+                data.bans.ban_name(target, reason);
+                data.sessions.remove(target);
+                data.queue.retain(|queued| queued != target);
+                if let Some(&Some(ref con)) = data.cons.get(target) {
+                    con.send(kick);
+                }
+            }
+        }
+
+        match data.bans.save(&data.conf.server.bans_file) {
+            Ok(_) => _AdminResult::ok(&format!("banned {}: {}", target, reason)),
+            Err(e) => _AdminResult::err(&format!("banned {} but failed saving the ban file: {}", target, e)),
+        }
+    }
+
+    pub fn unban(&self, target: &str) -> _AdminResult {
+        let mut data = self.write_data();
+        let lifted = match target.parse::<IpAddr>() {
+            Ok(ip) => data.bans.unban_ip(ip),
+            Err(_) => data.bans.unban_name(target),
+        };
+        if !lifted {
+            return _AdminResult::err(&format!("{} is not banned", target));
+        }
+
+        match data.bans.save(&data.conf.server.bans_file) {
+            Ok(_) => _AdminResult::ok(&format!("unbanned {}", target)),
+            Err(e) => _AdminResult::err(&format!("unbanned {} but failed saving the ban file: {}", target, e)),
+        }
+    }
+
+    fn list_bans(&self) -> _AdminResult {
+        let data = self.read_data();
+
+        let mut rows = Vec::new();
+        let bans = data.bans.names.iter().map(|ban| ("name", ban))
+            .chain(data.bans.ips.iter().map(|ban| ("ip", ban)));
+        for (kind, (target, ban)) in bans {
+            let mut row = BTreeMap::new();
+            row.insert(String::from("kind"), kind.to_string());
+            row.insert(String::from("target"), target.clone());
+            row.insert(String::from("reason"), ban.reason.clone());
+            rows.push(row);
+        }
+
+        let mut res = _AdminResult::ok(&format!("{} bans", rows.len()));
+        res.rows = rows;
+
+        res
+    }
+
+    // Read the config file again, along with the accounts and ban files
+    // it names. The listening address, UDP port and TLS settings only take
+    // effect after a restart, and clients that are already connected keep
+    // their old heartbeat and rate limits.
+    pub fn reload_conf(&self) -> io::Result<()> {
+        let path = self.read_data().conf_path.clone();
+        let conf = read_conf(&path)?;
+        let accounts = _Accounts::load(&conf.server.accounts_file)?;
+        let bans = _Bans::load(&conf.server.bans_file)?;
+        let unit_types = _UnitRegistry::load(&conf.server.units_dir)?;
+        let factions = names::load_factions(&conf.server.names_dir)?;
+
+        self.limiter.lock().unwrap().set_conf(conf.server.limits.clone());
+
+        let mut data = self.write_data();
+        data.conf = conf;
+        data.accounts = accounts;
+        data.bans = bans;
+        // Units already in the game keep the archetype they were made with.
+        data.unit_types = unit_types;
+        data.names.set_factions(factions);
+
+        info!("reloaded config {}", path);
+
+        Ok(())
+    }
+
+    // Deliver a chat message from a player. Returns why not if it can't be
+    // delivered.
+    fn chat(&self, key: &str, channel: ChatChannel, text: &str) -> Result<(), String> {
+        let mut data = self.write_data();
+        let (sender, team) = match data.players.get(key) {
+            Some(player) => (player.name.clone(), player.team.clone()),
+            None => return Err(format!("player {} is not logged in", key)),
+        };
+
+        deliver_chat(&mut data, &sender, team.as_ref().map(String::as_str), channel, text)
+    }
+
+    // Deliver a chat message from the server itself. Team messages go to
+    // everyone on `team`.
+    pub fn server_chat(&self, channel: ChatChannel, team: Option<&str>, text: &str) -> Result<(), String> {
+        let mut data = self.write_data();
+        let sender = data.name.clone();
+
+        deliver_chat(&mut data, &sender, team, channel, text)
+    }
+
+    // Give a player a new unit of a kind from the unit registry, named
+    // with gen_unit_name if it has no name. Returns the id of the new unit.
+    pub fn spawn_unit(&self, player: &str, unit_type: &str, name: Option<&str>, x: i64, y: i64) -> Result<EntityId, String> {
+        let mut data = self.write_data();
+        let bounds = data.map_bounds();
+        if x < 0 || y < 0 || x >= bounds.width || y >= bounds.height {
+            return Err(format!("({}, {}) is outside the {}x{} map", x, y, bounds.width, bounds.height));
+        }
+
+        if data.unit_types.get(unit_type).is_none() {
+            return Err(format!("no unit type named {}", unit_type));
+        }
+
+        let generated = name.is_none();
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => gen_unit_name(&data, player, unit_type)?,
+        };
+
+        // The id is only taken once the unit is in place, so a spawn that
+        // fails doesn't use one up.
+        let id = data.entities.peek();
+        let unit = data.unit_types.spawn(id, unit_type, &name, x, y)?;
+        match data.players.get_mut(player) {
+            Some(player) => player.add_unit(unit)?,
+            None => return Err(format!("no player named {}", player)),
+        }
+        data.entities.allocate();
+        if generated {
+            data.names.use_name(player);
+        }
+        debug!("spawned {} {} ({}) for player {} at ({}, {})", unit_type, id, name, player, x, y);
+
+        Ok(id)
+    }
+
+    // The name that none of a player's units has, that their next unit of
+    // some type would get.
+    pub fn gen_unit_name(&self, player: &str, unit_type: &str) -> Result<String, String> {
+        gen_unit_name(&self.read_data(), player, unit_type)
+    }
+
+    // Look up a unit, and the player that owns it, by id.
+    pub fn unit(&self, id: EntityId) -> Option<_OwnedUnit> {
+        let data = self.read_data();
+        let owner = data.unit_owner(id)?;
+        let unit = data.players.get(owner)?.units.get(&id)?;
+
+        Some(_OwnedUnit {
+            owner: owner.to_string(),
+            unit: unit.clone(),
+        })
+    }
+
+    pub fn unit_types(&self) -> BTreeMap<String, UnitArchetype> {
+        self.read_data().unit_types.archetypes()
+    }
+
+    // Move a unit, whoever owns it. Returns where it ended up, or why it
+    // couldn't move.
+    pub fn move_unit(&self, id: EntityId, x: i64, y: i64) -> Result<(i64, i64), String> {
+        let mut data = self.write_data();
+        let bounds = data.map_bounds();
+        let owner = match data.unit_owner(id) {
+            Some(owner) => owner.to_string(),
+            None => return Err(format!("no unit {}", id)),
+        };
+        match data.players.get_mut(&owner) {
+            Some(player) => player.move_unit(id, x, y, &bounds),
+            None => Err(format!("no player named {}", owner)),
+        }
+    }
+
+    // Forget the connection for a player, but keep the player around so
+    // they can log back in. Does nothing if the player has already logged
+    // in again on a newer connection.
+    fn disconnect(&self, key: &str, con_id: usize, reason: &str) {
+        self.udp_routes.lock().unwrap().remove(con_id);
+
+        {
+            let mut data = self.write_data();
+
+            let current = match data.cons.get(key) {
+                Some(&Some(ref con)) => con.id == con_id,
+                _ => false,
+            };
+            if !current {
+                debug!("replaced connection closed: {}: {}", key, reason);
+                return;
+            }
+
+            data.cons.insert(key.to_string(), None);
+            if let Some(session) = data.sessions.get_mut(key) {
+                session.disconnected_at = Some(Instant::now());
+            }
+            data.push_event(_Event::Disconnected {
+                player: key.to_string(),
+                reason: reason.to_string(),
+            });
+        }
+
+        info!("client disconnected: {}: {}", key, reason);
+    }
+}
+
+// Python wrapper for query_status(), for servers without TLS.
+fn py_query_status(py: Python, address: &str) -> PyResult<_Status> {
+    match py.allow_threads(|| query_status(address, false, None)) {
+        Ok(status) => Ok(_Status(status)),
+        Err(e) => Err(PyErr::new::<exc::IOError, _>(py, e.to_string())),
+    }
+}
+
+// Python wrapper for query_status(), for servers with TLS turned on.
+fn py_query_status_tls(py: Python, address: &str, pinned_fingerprint: Option<String>) -> PyResult<_Status> {
+    let pin = pinned_fingerprint.as_ref().map(String::as_str);
+    match py.allow_threads(|| query_status(address, true, pin)) {
+        Ok(status) => Ok(_Status(status)),
+        Err(e) => Err(PyErr::new::<exc::IOError, _>(py, e.to_string())),
+    }
+}
+
+fn read_conf(path: &str) -> io::Result<ServerConf> {
+    let mut f = File::open(path)?;
+    let mut contents = String::new();
+    f.read_to_string(&mut contents)?;
+
+    let conf: ServerConf = toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let tick_rate = conf.server.tick_rate;
+    if tick_rate == 0 || tick_rate > MAX_TICK_RATE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("tick_rate is {}, it must be from 1 to {}", tick_rate, MAX_TICK_RATE),
+        ));
+    }
+
+    Ok(conf)
+}
+
+// Make up a name for a unit of a player's, that none of their units has.
+fn gen_unit_name(data: &_Data, player: &str, unit_type: &str) -> Result<String, String> {
+    let player = match data.players.get(player) {
+        Some(player) => player,
+        None => return Err(format!("no player named {}", player)),
+    };
+
+    let faction = player.faction.as_ref().map(String::as_str);
+    Ok(data.names.generate(&player.name, faction, unit_type, |name| player.has_unit_named(name)))
+}
+
+// Send a chat message to everyone on its channel. Team messages go to the
+// players on `team`, and whispers to both the recipient and the sender.
+// Global messages are kept for players who join later.
+fn deliver_chat(data: &mut _Data, sender: &str, team: Option<&str>, channel: ChatChannel, text: &str) -> Result<(), String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(String::from("chat message is empty"));
+    }
+
+    let chat_conf = data.conf.server.chat.clone();
+    if text.chars().count() > chat_conf.max_length {
+        return Err(format!("chat message is longer than {} characters", chat_conf.max_length));
+    }
+
+    let recipients: Vec<String> = match channel {
+        ChatChannel::Global => data.cons.keys().cloned().collect(),
+        ChatChannel::Team => {
+            let team = match team {
+                Some(team) => team,
+                None => return Err(String::from("not on a team")),
+            };
+
+            data.players
+                .values()
+                .filter(|player| player.team.as_ref().map(String::as_str) == Some(team))
+                .map(|player| player.name.clone())
+                .collect()
+        }
+        ChatChannel::Whisper(ref to) => {
+            match data.cons.get(to) {
+                Some(&Some(_)) => {}
+                _ => return Err(format!("player {} is not connected", to)),
+            }
+
+            let mut recipients = vec![to.clone()];
+            if to != sender {
+                recipients.push(sender.to_string());
+            }
+            recipients
+        }
+    };
+
+    let msg = ChatMsg {
+        sender: sender.to_string(),
+        channel: channel.clone(),
+        text: text.to_string(),
+    };
+
+    for name in &recipients {
+        if let Some(&Some(ref con)) = data.cons.get(name) {
+            if con.capabilities & CAP_CHAT != 0 {
+                con.send(Message::Chat(msg.clone()));
+            }
+        }
+    }
+
+    info!("chat [{}] {}: {}", channel.name(), sender, text);
+
+    if channel == ChatChannel::Global {
+        data.chat_history.push_back(msg);
+        while data.chat_history.len() > chat_conf.history_len {
+            data.chat_history.pop_front();
+        }
+    }
+
+    data.push_event(_Event::Chat {
+        sender: sender.to_string(),
+        channel: channel,
+        text: text.to_string(),
+    });
+
+    Ok(())
+}
+
+// Tell a client why it is being turned away, and return the same reason as
+// an error for the caller to log.
+fn refuse(stream: &mut _MeteredStream, kind: ErrorKind, message: &str) -> io::Error {
+    let e = ErrorMsg::new(kind, message);
+    if let Err(write_err) = write_message(stream, &Message::Error(e.clone())) {
+        debug!("failed sending error to client: {:?}", write_err);
+    }
+
+    io::Error::other(e.to_string())
 }
 
 // #[cfg(test)]
@@ -0,0 +1,35 @@
+use rand;
+use rand::Rng;
+
+use std::time::{Duration, Instant};
+
+// Lets a player whose connection dropped rejoin as the same player, by
+// sending back the token they were given when they logged in.
+#[derive(Debug, Clone)]
+pub struct _Session {
+    pub token: String,
+
+    // When the player's last connection closed, or None while they are
+    // connected.
+    pub disconnected_at: Option<Instant>,
+}
+
+impl _Session {
+    pub fn new() -> Self {
+        let mut rng = rand::thread_rng();
+
+        _Session {
+            token: format!("{:016x}{:016x}", rng.gen::<u64>(), rng.gen::<u64>()),
+            disconnected_at: None,
+        }
+    }
+
+    // A session can be resumed while the player is connected, and for a
+    // while after they disconnect.
+    pub fn is_expired(&self, timeout: Duration) -> bool {
+        match self.disconnected_at {
+            Some(at) => at.elapsed() > timeout,
+            None => false,
+        }
+    }
+}
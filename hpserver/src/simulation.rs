@@ -0,0 +1,144 @@
+use cpython::{PyDict, Python, ToPyObject};
+use event::_Event;
+use hpnet::message::Command;
+use player::_Player;
+use std::cmp;
+use std::collections::HashMap;
+use std::time::Duration;
+use unit::MapBounds;
+
+// A command from a player, stamped with the tick it runs on when it's
+// queued. The seed and these, in order, are all it takes to play a game
+// over again.
+#[derive(Debug, Clone)]
+pub struct _TickCommand {
+    pub tick: u64,
+    pub player: String,
+    pub command: Command,
+}
+
+impl ToPyObject for _TickCommand {
+    type ObjectType = PyDict;
+
+    fn to_py_object(&self, py: Python) -> PyDict {
+        let dict = PyDict::new(py);
+        dict.set_item(py, "tick", self.tick).unwrap();
+        dict.set_item(py, "player", self.player.clone()).unwrap();
+        match self.command {
+            Command::Move { unit, x, y } => {
+                dict.set_item(py, "type", "move").unwrap();
+                dict.set_item(py, "unit", unit).unwrap();
+                dict.set_item(py, "x", x).unwrap();
+                dict.set_item(py, "y", y).unwrap();
+            }
+        }
+
+        dict
+    }
+}
+
+// Run one tick of the game: carry out the commands stamped with it, in
+// the order they arrived, then move every unit one step along its orders.
+// Players and units are always visited in the same order, so the same
+// seed and commands give the same game every time. Returns events for
+// commands and moves that failed.
+pub fn step(players: &mut HashMap<String, _Player>, tick: u64, commands: &[_TickCommand], bounds: &MapBounds) -> Vec<_Event> {
+    let mut events = Vec::new();
+
+    for cmd in commands {
+        if cmd.tick != tick {
+            warn!("command from player {} for tick {} ran on tick {}", cmd.player, cmd.tick, tick);
+        }
+
+        let res = match players.get_mut(&cmd.player) {
+            Some(player) => match cmd.command {
+                Command::Move { unit, x, y } => player.order_unit(unit, x, y),
+            },
+            None => Err(format!("no player named {}", cmd.player)),
+        };
+
+        if let Err(reason) = res {
+            debug!("command from player {} failed on tick {}: {:?}: {}", cmd.player, tick, cmd.command, reason);
+            events.push(_Event::CommandFailed {
+                player: cmd.player.clone(),
+                reason: reason,
+            });
+        }
+    }
+
+    let mut names: Vec<String> = players.keys().cloned().collect();
+    names.sort();
+    for name in names {
+        let player = players.get_mut(&name).unwrap();
+        for (unit, reason) in player.advance_units(bounds) {
+            debug!("unit {} of player {} stopped: {}", unit, name, reason);
+            events.push(_Event::CommandFailed {
+                player: name.clone(),
+                reason: format!("unit {} stopped: {}", unit, reason),
+            });
+        }
+    }
+
+    events
+}
+
+// How the tick loop is keeping up.
+#[derive(Debug, Clone, Default)]
+pub struct _TickStats {
+    // The seed the game was started with.
+    pub seed: u64,
+    // Ticks per second the loop is aiming for.
+    pub rate: u32,
+    // Ticks run so far.
+    pub ticks: u64,
+    pub last_secs: f64,
+    pub max_secs: f64,
+    total_secs: f64,
+    // Ticks that took longer than a tick should.
+    pub overruns: u64,
+    // Ticks dropped to catch up after falling too far behind.
+    pub skipped: u64,
+}
+
+impl _TickStats {
+    pub fn new(seed: u64, rate: u32) -> Self {
+        _TickStats {
+            seed: seed,
+            rate: rate,
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&mut self, duration: Duration, tick: Duration) {
+        let secs = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0;
+        self.ticks += 1;
+        self.last_secs = secs;
+        self.max_secs = self.max_secs.max(secs);
+        self.total_secs += secs;
+        if duration > tick {
+            self.overruns += 1;
+        }
+    }
+
+    pub fn average_secs(&self) -> f64 {
+        self.total_secs / cmp::max(self.ticks, 1) as f64
+    }
+}
+
+impl ToPyObject for _TickStats {
+    type ObjectType = PyDict;
+
+    fn to_py_object(&self, py: Python) -> PyDict {
+        let dict = PyDict::new(py);
+        dict.set_item(py, "seed", self.seed).unwrap();
+        dict.set_item(py, "rate", self.rate).unwrap();
+        dict.set_item(py, "ticks", self.ticks).unwrap();
+        dict.set_item(py, "last_secs", self.last_secs).unwrap();
+        dict.set_item(py, "average_secs", self.average_secs()).unwrap();
+        dict.set_item(py, "max_secs", self.max_secs).unwrap();
+        dict.set_item(py, "overruns", self.overruns).unwrap();
+        dict.set_item(py, "skipped", self.skipped).unwrap();
+
+        dict
+    }
+}
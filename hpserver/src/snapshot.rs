@@ -0,0 +1,156 @@
+use hpnet::datagram::{Datagram, Delta, Snapshot};
+use hpnet::delta::{diff, History, UnitMap};
+use hpnet::message::CAP_DELTA_SNAPSHOTS;
+
+// How many snapshots to remember for each client, to make deltas against.
+const HISTORY_LEN: usize = 32;
+
+// The snapshots we've sent one client over UDP, and the newest one it has
+// acknowledged.
+#[derive(Debug)]
+pub struct _ClientSnapshots {
+    history: History,
+    acked: Option<u64>,
+    // Clients that can't apply deltas get every snapshot whole.
+    deltas: bool,
+}
+
+impl _ClientSnapshots {
+    pub fn new(capabilities: u32) -> Self {
+        _ClientSnapshots {
+            history: History::new(HISTORY_LEN),
+            acked: None,
+            deltas: capabilities & CAP_DELTA_SNAPSHOTS != 0,
+        }
+    }
+
+    pub fn ack(&mut self, seq: u64) {
+        if !self.deltas {
+            return;
+        }
+
+        let newer = match self.acked {
+            Some(acked) => seq > acked,
+            None => true,
+        };
+
+        if newer && self.history.get(seq).is_some() {
+            self.acked = Some(seq);
+        }
+    }
+
+    // The client lost track, so forget what it acknowledged.
+    pub fn resync(&mut self) {
+        self.acked = None;
+        self.history.clear();
+    }
+
+    // Make the datagram for the next snapshot: the changes since the newest
+    // snapshot the client acknowledged, or the whole snapshot if it hasn't
+    // acknowledged one we still remember. It still needs splitting into
+    // parts to send.
+    pub fn next(&mut self, seq: u64, units: &UnitMap) -> Datagram {
+        let base = match self.acked {
+            Some(acked) => self.history.get(acked).map(|base| (acked, base)),
+            None => None,
+        };
+
+        let datagram = match base {
+            Some((base_seq, base)) => {
+                let (changed, removed) = diff(base, units);
+                Datagram::Delta(Delta {
+                    seq: seq,
+                    base: base_seq,
+                    part: 0,
+                    parts: 1,
+                    changed: changed,
+                    removed: removed,
+                })
+            }
+            None => Datagram::Snapshot(Snapshot {
+                seq: seq,
+                part: 0,
+                parts: 1,
+                units: units.values().cloned().collect(),
+            }),
+        };
+
+        self.history.push(seq, units.clone());
+
+        datagram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hpnet::delta::unit_map;
+    use hpnet::message::UnitState;
+
+    fn units(x: i64) -> UnitMap {
+        unit_map(vec![UnitState {
+            id: 1,
+            owner: String::from("Harold"),
+            unit_type: String::from("scout"),
+            name: String::from("Scout 1"),
+            x: x,
+            y: 0,
+        }])
+    }
+
+    fn base(datagram: &Datagram) -> Option<u64> {
+        match *datagram {
+            Datagram::Delta(ref delta) => Some(delta.base),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn deltas_are_made_against_the_newest_ack() {
+        let mut client = _ClientSnapshots::new(CAP_DELTA_SNAPSHOTS);
+
+        assert_eq!(base(&client.next(1, &units(0))), None);
+        assert_eq!(base(&client.next(2, &units(1))), None);
+
+        client.ack(2);
+        client.ack(1);
+        assert_eq!(base(&client.next(3, &units(2))), Some(2));
+    }
+
+    #[test]
+    fn snapshots_are_sent_whole_once_the_ack_is_forgotten() {
+        let mut client = _ClientSnapshots::new(CAP_DELTA_SNAPSHOTS);
+        client.next(1, &units(0));
+        client.ack(1);
+
+        // The client's acks stop arriving, until its base falls out of the
+        // history.
+        for seq in 2..(HISTORY_LEN as u64 + 2) {
+            assert_eq!(base(&client.next(seq, &units(seq as i64))), Some(1));
+        }
+        assert_eq!(base(&client.next(HISTORY_LEN as u64 + 2, &units(0))), None);
+    }
+
+    #[test]
+    fn resync_sends_the_next_snapshot_whole() {
+        let mut client = _ClientSnapshots::new(CAP_DELTA_SNAPSHOTS);
+        client.next(1, &units(0));
+        client.ack(1);
+
+        client.resync();
+        assert_eq!(base(&client.next(2, &units(1))), None);
+
+        // Acks for snapshots from before the resync are ignored.
+        client.ack(1);
+        assert_eq!(base(&client.next(3, &units(2))), None);
+    }
+
+    #[test]
+    fn clients_without_deltas_always_get_whole_snapshots() {
+        let mut client = _ClientSnapshots::new(0);
+        client.next(1, &units(0));
+        client.ack(1);
+
+        assert_eq!(base(&client.next(2, &units(1))), None);
+    }
+}
@@ -0,0 +1,92 @@
+use cpython::{PyDict, Python, ToPyObject};
+use hpnet::frame::{read_message, write_message};
+use hpnet::message::{Message, ServerStatus};
+use hpnet::stream::Stream;
+use hpnet::tls::{fingerprint, fingerprint_matches};
+use native_tls::TlsConnector;
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+
+// How long to wait for a server to answer a status query.
+const QUERY_TIMEOUT_SECS: u64 = 10;
+
+// A server's status, as handed to scripts.
+#[derive(Debug, Clone)]
+pub struct _Status(pub ServerStatus);
+
+impl ToPyObject for _Status {
+    type ObjectType = PyDict;
+
+    fn to_py_object(&self, py: Python) -> PyDict {
+        let dict = PyDict::new(py);
+        dict.set_item(py, "name", self.0.name.clone()).unwrap();
+        dict.set_item(py, "protocol_version", self.0.protocol_version).unwrap();
+        dict.set_item(py, "map", self.0.map.clone()).unwrap();
+        dict.set_item(py, "players", self.0.players.clone()).unwrap();
+        dict.set_item(py, "max_players", self.0.max_players).unwrap();
+        dict.set_item(py, "uptime_secs", self.0.uptime_secs).unwrap();
+        dict.set_item(py, "tick_rate", self.0.tick_rate).unwrap();
+
+        dict
+    }
+}
+
+// Ask a running server for its status without logging in. Servers with
+// TLS turned on need tls set. Their certificate is checked against the
+// pinned fingerprint if there is one, and not at all otherwise, since
+// servers usually have self-signed certificates.
+pub fn query_status(address: &str, tls: bool, pinned_fingerprint: Option<&str>) -> io::Result<ServerStatus> {
+    let tcp = TcpStream::connect(address)?;
+    tcp.set_read_timeout(Some(Duration::from_secs(QUERY_TIMEOUT_SECS)))?;
+
+    let mut stream = if tls {
+        Stream::Tls(connect_tls(tcp, pinned_fingerprint)?)
+    } else {
+        Stream::Plain(tcp)
+    };
+
+    write_message(&mut stream, &Message::QueryStatus)?;
+
+    match read_message(&mut stream)? {
+        Message::Status(status) => Ok(status),
+        Message::Error(e) => Err(io::Error::other(e.message)),
+        msg => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected a status message but got: {:?}", msg),
+        )),
+    }
+}
+
+fn connect_tls(tcp: TcpStream, pinned_fingerprint: Option<&str>) -> io::Result<::native_tls::TlsStream<TcpStream>> {
+    if pinned_fingerprint.is_none() {
+        warn!("not checking the tls certificate of the server being queried");
+    }
+
+    let connector = TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .build()
+        .map_err(io::Error::other)?;
+
+    // The name doesn't matter, since it isn't checked.
+    let stream = connector
+        .connect("localhost", tcp)
+        .map_err(|e| io::Error::other(format!("tls handshake failed: {}", e)))?;
+
+    if let Some(pin) = pinned_fingerprint {
+        let der = match stream.peer_certificate() {
+            Ok(Some(cert)) => cert.to_der().map_err(io::Error::other)?,
+            _ => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "server sent no tls certificate")),
+        };
+
+        if !fingerprint_matches(&fingerprint(&der), pin) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "server tls certificate doesn't match the pinned fingerprint",
+            ));
+        }
+    }
+
+    Ok(stream)
+}
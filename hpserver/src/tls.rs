@@ -0,0 +1,88 @@
+extern crate rcgen;
+
+use conf::ServerConfTls;
+
+use hpnet::tls::fingerprint;
+use native_tls::{Certificate, Identity, TlsAcceptor};
+use std::fs::{self, File};
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+// Where a self-signed certificate and its key are kept, in the directory
+// of the config file.
+const SELF_SIGNED_CERT_FILE: &str = "selfsigned_cert.pem";
+const SELF_SIGNED_KEY_FILE: &str = "selfsigned_key.pem";
+
+// Build the TLS acceptor described by the server config, or None if TLS
+// is turned off. A self-signed certificate is kept in `dir`, and `ip` goes
+// in its subject when it's made.
+pub fn acceptor(conf: &ServerConfTls, ip: &str, dir: &Path) -> io::Result<Option<TlsAcceptor>> {
+    if !conf.enabled {
+        return Ok(None);
+    }
+
+    let (cert_pem, key_pem) = if conf.self_signed {
+        self_signed(ip, dir)?
+    } else {
+        match (&conf.cert_file, &conf.key_file) {
+            (&Some(ref cert_file), &Some(ref key_file)) => (read_file(cert_file)?, read_file(key_file)?),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "tls needs cert_file and key_file, or self_signed = true",
+                ));
+            }
+        }
+    };
+
+    let cert = Certificate::from_pem(cert_pem.as_bytes()).map_err(to_io_error)?;
+    let der = cert.to_der().map_err(to_io_error)?;
+    info!("tls certificate fingerprint: {}", fingerprint(&der));
+
+    let identity = Identity::from_pkcs8(cert_pem.as_bytes(), key_pem.as_bytes()).map_err(to_io_error)?;
+    let acceptor = TlsAcceptor::new(identity).map_err(to_io_error)?;
+
+    Ok(Some(acceptor))
+}
+
+// The certificate and key made the first time the server ran, as PEM, or
+// new ones if there aren't any yet. They're saved, so the fingerprint
+// clients pin stays the same across restarts. Only for development, since
+// clients have no way to trust it except by pinning its fingerprint.
+fn self_signed(ip: &str, dir: &Path) -> io::Result<(String, String)> {
+    warn!("using a self-signed tls certificate, don't do this in production");
+
+    let cert_path = dir.join(SELF_SIGNED_CERT_FILE);
+    let key_path = dir.join(SELF_SIGNED_KEY_FILE);
+    if cert_path.exists() && key_path.exists() {
+        info!("using the self-signed tls certificate in {}", cert_path.display());
+        return Ok((fs::read_to_string(&cert_path)?, fs::read_to_string(&key_path)?));
+    }
+
+    let names = vec![String::from("localhost"), String::from(ip)];
+    let cert = rcgen::generate_simple_self_signed(names).map_err(to_io_error)?;
+    let cert_pem = cert.serialize_pem().map_err(to_io_error)?;
+    let key_pem = cert.serialize_private_key_pem();
+
+    fs::write(&key_path, &key_pem)?;
+    fs::write(&cert_path, &cert_pem)?;
+    info!("saved a new self-signed tls certificate to {}", cert_path.display());
+
+    Ok((cert_pem, key_pem))
+}
+
+fn read_file(path: &str) -> io::Result<String> {
+    let mut f = File::open(path)?;
+    let mut contents = String::new();
+    f.read_to_string(&mut contents)?;
+
+    Ok(contents)
+}
+
+fn to_io_error<E>(e: E) -> io::Error
+where
+    E: Into<Box<::std::error::Error + Send + Sync>>,
+{
+    io::Error::other(e)
+}
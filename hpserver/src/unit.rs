@@ -1,41 +1,95 @@
-use std::collections::HashMap;
-use std::vec::Vec;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::marker::Send;
-use cpython::{Python, ToPyObject, PyDict};
+use cpython::{Python, ToPyObject, PyDict, PyString};
 use std::clone::Clone;
+use std::cmp;
+use hpnet::message::EntityId;
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone)]
-pub enum UnitType {
-  Scout,
+// The name of a kind of unit, from the server's unit registry. What each
+// kind can do is in its archetype.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Ord, PartialOrd)]
+pub struct UnitType(pub String);
+
+impl UnitType {
+  pub fn name(&self) -> &str {
+    &self.0
+  }
 }
 
+// Just the name.
 impl ToPyObject for UnitType {
-  type ObjectType = PyDict;
+  type ObjectType = PyString;
 
-  fn to_py_object(&self, py: Python) -> PyDict {
-    match self {
-      UnitType::Scout => {
-        let dict = PyDict::new(py);
-        // dict.set_item(py, "server", self.server.clone()).unwrap(); // TODO
-        dict
-      }
+  fn to_py_object(&self, py: Python) -> PyString {
+    PyString::new(py, &self.0)
+  }
+}
+
+// The size of the map units move around on. Positions go from 0 up to,
+// but not including, the width and height.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapBounds {
+  pub width: i64,
+  pub height: i64,
+}
+
+impl MapBounds {
+  // Where a unit at pos ends up after trying to move x and y steps,
+  // stopping at the edges of the map. A unit that can't move at all
+  // because it's already against the edge it's heading for is blocked.
+  pub fn step(&self, pos: (i64, i64), x: i64, y: i64) -> Result<(i64, i64), String> {
+    let new_x = clamp(pos.0.saturating_add(x), self.width);
+    let new_y = clamp(pos.1.saturating_add(y), self.height);
+
+    if (new_x, new_y) == pos && (x != 0 || y != 0) {
+      return Err(format!(
+        "blocked by the edge of the {}x{} map at ({}, {})",
+        self.width, self.height, pos.0, pos.1
+      ));
     }
+
+    Ok((new_x, new_y))
   }
 }
 
+fn clamp(n: i64, size: i64) -> i64 {
+  cmp::max(0, cmp::min(n, size - 1))
+}
+
 pub trait Unit: Debug + Send + Sync {
-  // Move x steps in the x direction, and y steps in the y direction.
-  // Returns the world position of the unit after moving.
-  fn go(&self, x: i64, y: i64) -> (i64, i64);
+  // Move x steps in the x direction, and y steps in the y direction,
+  // stopping at the edges of the map. Returns the world position of the
+  // unit after moving, or why it couldn't move.
+  fn go(&mut self, x: i64, y: i64, bounds: &MapBounds) -> Result<(i64, i64), String>;
+
+  // Order the unit to move x and y steps, one step per tick, replacing
+  // any orders it had.
+  fn order(&mut self, x: i64, y: i64);
+
+  // Take the next step of the unit's orders. A unit that's blocked gives
+  // up on the rest of them.
+  fn advance(&mut self, bounds: &MapBounds) -> Result<(), String>;
+
+  // The id the server gave the unit when it was made. See _Entities.
+  fn id(&self) -> EntityId;
+
+  fn unit_type(&self) -> UnitType;
+
+  // Hit points the unit has left.
+  fn hp(&self) -> u32;
 
   // Get the world position of the unit.
   fn pos(&self) -> (i64, i64);
 
+  // Get the unit's name.
+  fn name(&self) -> String;
+
   fn box_clone(&self) -> Box<Unit>;
 }
 
-pub type Units = HashMap<UnitType, Vec<Box<Unit>>>;
+// A player's units, by id.
+pub type Units = BTreeMap<EntityId, Box<Unit>>;
 
 impl Clone for Box<Unit> {
   fn clone(&self) -> Self {
@@ -47,6 +101,14 @@ impl ToPyObject for Box<Unit> {
   type ObjectType = PyDict;
 
   fn to_py_object(&self, py: Python) -> PyDict {
-    PyDict::new(py)
+    let (x, y) = self.pos();
+    let dict = PyDict::new(py);
+    dict.set_item(py, "id", self.id()).unwrap();
+    dict.set_item(py, "name", self.name()).unwrap();
+    dict.set_item(py, "type", self.unit_type()).unwrap();
+    dict.set_item(py, "hp", self.hp()).unwrap();
+    dict.set_item(py, "x", x).unwrap();
+    dict.set_item(py, "y", y).unwrap();
+    dict
   }
 }